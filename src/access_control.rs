@@ -0,0 +1,81 @@
+//! Role-based access control, modelled on OpenZeppelin's `AccessControl`.
+//!
+//! Each role is a `bytes32` id with its own set of members and an admin role whose
+//! members may grant and revoke it. Every role is administered by `ADMIN_ROLE` unless
+//! reassigned.
+
+use stylus_sdk::{
+    alloy_primitives::{Address, B256},
+    alloy_sol_types::sol,
+    keccak_const::Keccak256,
+    prelude::*,
+};
+
+use crate::{MissingRole, SubscriptionError};
+
+// Zero like OpenZeppelin's DEFAULT_ADMIN_ROLE, so unconfigured roles fall back to it
+pub const ADMIN_ROLE: B256 = B256::ZERO;
+pub const GUARDIAN_ROLE: B256 = B256::new(Keccak256::new().update(b"GUARDIAN_ROLE").finalize());
+pub const KEEPER_ROLE: B256 = B256::new(Keccak256::new().update(b"KEEPER_ROLE").finalize());
+pub const FEE_MANAGER_ROLE: B256 = B256::new(Keccak256::new().update(b"FEE_MANAGER_ROLE").finalize());
+pub const PROVIDER_VERIFIER_ROLE: B256 = B256::new(Keccak256::new().update(b"PROVIDER_VERIFIER_ROLE").finalize());
+
+sol! {
+    event RoleGranted(bytes32 indexed role, address indexed account, address indexed sender);
+    event RoleRevoked(bytes32 indexed role, address indexed account, address indexed sender);
+    event RoleAdminChanged(bytes32 indexed role, bytes32 indexed previousAdminRole, bytes32 indexed newAdminRole);
+}
+
+sol_storage! {
+    pub struct AccessControl {
+        mapping(bytes32 => mapping(address => bool)) members;
+        mapping(bytes32 => bytes32) admin_roles;
+    }
+}
+
+impl AccessControl {
+    pub fn has_role(&self, role: B256, account: Address) -> bool {
+        self.members.getter(role).get(account)
+    }
+
+    pub fn role_admin(&self, role: B256) -> B256 {
+        self.admin_roles.get(role)
+    }
+
+    pub fn check_role(&self, role: B256, account: Address) -> Result<(), SubscriptionError> {
+        if !self.has_role(role, account) {
+            return Err(SubscriptionError::MissingRole(MissingRole { account, role }));
+        }
+        Ok(())
+    }
+
+    // Returns false without emitting when the account already holds the role
+    pub fn grant(&mut self, role: B256, account: Address, sender: Address) -> bool {
+        if self.has_role(role, account) {
+            return false;
+        }
+        self.members.setter(role).insert(account, true);
+        log(self.vm(), RoleGranted { role, account, sender });
+        true
+    }
+
+    // Returns false without emitting when the account does not hold the role
+    pub fn revoke(&mut self, role: B256, account: Address, sender: Address) -> bool {
+        if !self.has_role(role, account) {
+            return false;
+        }
+        self.members.setter(role).insert(account, false);
+        log(self.vm(), RoleRevoked { role, account, sender });
+        true
+    }
+
+    pub fn set_role_admin(&mut self, role: B256, admin_role: B256) {
+        let previous_admin_role = self.role_admin(role);
+        self.admin_roles.insert(role, admin_role);
+        log(self.vm(), RoleAdminChanged {
+            role,
+            previousAdminRole: previous_admin_role,
+            newAdminRole: admin_role
+        });
+    }
+}
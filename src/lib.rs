@@ -1,39 +1,331 @@
 #![cfg_attr(not(any(feature = "export-abi", test)), no_main)]
+#![recursion_limit = "256"]
 extern crate alloc;
 use alloc::{string::String, vec::Vec};
 
+pub mod access_control;
+pub mod utils;
+
+use access_control::{AccessControl, ADMIN_ROLE, FEE_MANAGER_ROLE, GUARDIAN_ROLE, KEEPER_ROLE, PROVIDER_VERIFIER_ROLE};
 use stylus_sdk::{
-    alloy_primitives::{Address, U256}, 
+    alloy_primitives::{Address, B256, U256, U8}, 
     prelude::*,
-    alloy_sol_types::sol,
-    evm,
+    alloy_sol_types::{sol, SolCall, SolValue},
+    crypto::keccak,
+    keccak_const::Keccak256,
     abi::Bytes,
+    stylus_core::calls::context::Call,
 };
 
-// Define simplified Solidity error types
+// Protocol fee bounds, in basis points
+pub const MAX_PROTOCOL_FEE_BPS: u64 = 1_000; // 10%
+pub const DEFAULT_PROTOCOL_FEE_BPS: u64 = 250; // 2.5%
+const DEFAULT_FEE_CHANGE_DELAY: u64 = 7 * 24 * 60 * 60; // 7 days
+pub const MIN_FEE_CHANGE_DELAY: u64 = 24 * 60 * 60; // 1 day
+pub const MAX_KEEPER_FEE_BPS: u64 = 1_000; // 10%
+
+// Notice subscribers get before a plan's new price or interval applies to their renewals
+pub const PLAN_CHANGE_NOTICE_PERIOD: u64 = 7 * 24 * 60 * 60; // 7 days
+
+// One status bit per id in the returned bitmap
+pub const MAX_BATCH_SIZE: usize = 256;
+
+// Upper bound on entries returned by the due-queue views
+pub const MAX_DUE_QUERY: usize = 256;
+
+// Upper bound on bucket entries and empty buckets a single checker_all page may visit
+pub const MAX_DUE_SCAN: usize = 1_024;
+
+// Width of the due-time buckets checker_all pages through
+pub const DUE_BUCKET_SPAN: u64 = 60 * 60; // 1 hour
+
+pub const MAX_PROVIDER_NAME_LENGTH: usize = 100;
+pub const MAX_METADATA_URI_LENGTH: usize = 256;
+
+// EIP-712 domain and intent types for relayed (gasless) calls
+pub const EIP712_NAME: &str = "SubscriptionEscrow";
+pub const EIP712_VERSION: &str = "1";
+pub const SUBSCRIBE_TYPEHASH: [u8; 32] = Keccak256::new()
+    .update(b"Subscribe(address subscriber,uint256 planId,uint256 nonce,uint256 deadline)")
+    .finalize();
+pub const CANCEL_TYPEHASH: [u8; 32] = Keccak256::new()
+    .update(b"CancelSubscription(address subscriber,uint256 subscriptionId,uint256 nonce,uint256 deadline)")
+    .finalize();
+pub const WITHDRAW_ESCROW_TYPEHASH: [u8; 32] = Keccak256::new()
+    .update(b"WithdrawEscrow(address user,address token,uint256 amount,uint256 nonce,uint256 deadline)")
+    .finalize();
+
+// Payment token used for plans and balances denominated in ETH
+pub const NATIVE_TOKEN: Address = Address::ZERO;
+
+// Longest billing interval a plan may use, which keeps due times far below 2^64
+pub const MAX_PLAN_INTERVAL: u64 = 5 * 365 * 24 * 60 * 60; // 5 years
+
+// Longest grace period a plan may give a past-due subscriber
+pub const MAX_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60; // 30 days
+
+// Longest free trial a plan may offer
+pub const MAX_TRIAL_DURATION: u64 = 90 * 24 * 60 * 60; // 90 days
+
+// Solidity error types, each carrying enough context for a frontend to explain the failure
 sol! {
-    error Unauthorized();
-    error InvalidInput();
-    error InsufficientFunds();
-    error NotFound();
+    // Access control
+    #[derive(Debug)]
+    error Unauthorized(address caller);
+    #[derive(Debug)]
+    error MissingRole(address account, bytes32 role);
+    #[derive(Debug)]
+    error AdminRoleTransferOnly();
+    #[derive(Debug)]
+    error SignatureExpired(uint256 deadline);
+    #[derive(Debug)]
+    error InvalidSignature(address signer);
+    #[derive(Debug)]
+    error AlreadyInitialized();
+    #[derive(Debug)]
+    error ReentrantCall();
+    #[derive(Debug)]
+    error ZeroAddress();
+    #[derive(Debug)]
+    error ContractPaused();
+    #[derive(Debug)]
+    error ContractNotPaused();
+
+    // Providers and plans
+    #[derive(Debug)]
+    error ProviderAlreadyRegistered(address provider);
+    #[derive(Debug)]
+    error ProviderNotRegistered(address provider);
+    #[derive(Debug)]
+    error NameTooLong(uint256 length, uint256 maxLength);
+    #[derive(Debug)]
+    error MetadataUriTooLong(uint256 length, uint256 maxLength);
+    #[derive(Debug)]
+    error InvalidPlanParameters(uint256 price, uint256 interval);
+    #[derive(Debug)]
+    error InvalidRefundPolicy(uint8 policy);
+    #[derive(Debug)]
+    error GracePeriodTooLong(uint256 gracePeriod, uint256 maxGracePeriod);
+    #[derive(Debug)]
+    error TrialTooLong(uint256 duration, uint256 maxDuration);
+    #[derive(Debug)]
+    error PlanNotFound(uint256 planId);
+    #[derive(Debug)]
+    error PlanInactive(uint256 planId);
+    #[derive(Debug)]
+    error PlanAlreadyActive(uint256 planId);
+
+    // Subscriptions and payments
+    #[derive(Debug)]
+    error SubscriptionNotActive(uint256 subscriptionId);
+    #[derive(Debug)]
+    error PaymentNotDue(uint256 subscriptionId, uint256 dueAt);
+    #[derive(Debug)]
+    error InvalidCycleCount(uint256 cycles, uint256 maxCycles);
+    #[derive(Debug)]
+    error InvalidBatchSize(uint256 size, uint256 maxSize);
+
+    // Balances and transfers
+    #[derive(Debug)]
+    error ZeroAmount();
+    #[derive(Debug)]
+    error InsufficientEscrow(uint256 required, uint256 available);
+    #[derive(Debug)]
+    error InsufficientTreasury(uint256 required, uint256 available);
+    #[derive(Debug)]
+    error NothingToWithdraw(address account);
+    #[derive(Debug)]
+    error TransferFailed(address to, uint256 amount);
+    #[derive(Debug)]
+    error TokenNotAllowed(address token);
+    #[derive(Debug)]
+    error TokenPullFailed(address token, address from, uint256 amount);
+    #[derive(Debug)]
+    error AllowanceRequiresToken(uint256 planId);
+    #[derive(Debug)]
+    error PermitFailed(address token, address owner);
+    #[derive(Debug)]
+    error PermitAmountTooLow(uint256 amount, uint256 required);
+
+    // Fees and keepers
+    #[derive(Debug)]
+    error FeeTooHigh(uint256 fee, uint256 maxFee);
+    #[derive(Debug)]
+    error InvalidFeeRange(uint256 minFee, uint256 maxFee);
+    #[derive(Debug)]
+    error NoPendingFeeChange();
+    #[derive(Debug)]
+    error FeeChangeNotReady(uint256 effectiveAt);
+    #[derive(Debug)]
+    error FeeDelayTooShort(uint256 delay, uint256 minDelay);
+    #[derive(Debug)]
+    error KeeperAlreadyAdded(address keeper);
+    #[derive(Debug)]
+    error KeeperNotFound(address keeper);
 }
 
 // Events for monitoring
 sol! {
     event ProviderRegistered(address indexed provider, string name);
-    event PlanCreated(uint256 indexed planId, address indexed provider, uint256 price, uint256 interval);
+    event ProviderVerificationUpdated(address indexed provider, bool verified);
+    event ProviderProfileUpdated(address indexed provider, string name, string metadataUri, address indexed payoutAddress);
+    event PlanCreated(uint256 indexed planId, address indexed provider, address indexed token, uint256 price, uint256 interval);
     event SubscriptionCreated(uint256 indexed subscriptionId, address indexed user, uint256 indexed planId);
-    event PaymentProcessed(address indexed from, address indexed to, uint256 amount);
-    event EarningsWithdrawn(address indexed provider, uint256 amount);
+    event PaymentProcessed(address indexed from, address indexed to, address indexed token, uint256 amount);
+    event EarningsWithdrawn(address indexed provider, address indexed token, uint256 amount);
+    event EscrowWithdrawn(address indexed user, address indexed token, uint256 amount);
+    event TokenDeposited(address indexed user, address indexed token, uint256 amount);
+    event PlanRefundPolicyUpdated(uint256 indexed planId, uint8 policy, uint256 coolingOffPeriod);
+    event SubscriptionCancelled(uint256 indexed subscriptionId, address indexed cancelledBy, uint256 refundAmount);
+    event TreasuryUpdated(address indexed previousTreasury, address indexed newTreasury);
+    event AdminTransferStarted(address indexed currentAdmin, address indexed pendingAdmin);
+    event AdminTransferred(address indexed previousAdmin, address indexed newAdmin);
+    event AdminRenounced(address indexed previousAdmin);
+    event ProtocolFeesWithdrawn(address indexed to, address indexed token, uint256 amount);
+    event ProtocolFeeChangeScheduled(uint256 currentFee, uint256 newFee, uint256 effectiveAt);
+    event ProtocolFeeChanged(uint256 previousFee, uint256 newFee);
+    event ProtocolFeeDelayUpdated(uint256 delay);
+    event PermissionlessProcessingUpdated(bool enabled);
+    event KeeperFeeUpdated(uint256 feeBps, uint256 minFee, uint256 maxFee);
+    event TokenKeeperFeeUpdated(address indexed token, uint256 minFee, uint256 maxFee);
+    event KeeperEarningsWithdrawn(address indexed keeper, address indexed token, uint256 amount);
+    event PaymentTokenUpdated(address indexed token, bool allowed);
+    event Paused(address indexed by);
+    event Unpaused(address indexed by);
+    event IntentExecuted(address indexed signer, address indexed relayer, uint256 nonce);
+    event BatchPaymentsProcessed(address indexed keeper, uint256 processed, uint256 skipped, uint256 statusBitmap);
+    event PlanPriceChangeScheduled(uint256 indexed planId, uint256 currentPrice, uint256 newPrice, uint256 newInterval, uint256 effectiveAt);
+    event PlanUpdated(uint256 indexed planId, uint256 price, uint256 interval);
+    event PlanDeactivated(uint256 indexed planId);
+    event PlanReactivated(uint256 indexed planId);
+    event PlanGracePeriodUpdated(uint256 indexed planId, uint256 gracePeriod);
+    event PlanMaxCyclesUpdated(uint256 indexed planId, uint256 maxCycles);
+    event PlanTrialDurationUpdated(uint256 indexed planId, uint256 trialDuration);
+    event TrialStarted(uint256 indexed subscriptionId, address indexed user, uint256 trialEndsAt);
+    event AllowanceFundingUpdated(uint256 indexed subscriptionId, bool enabled);
+    event SubscriptionExpired(uint256 indexed subscriptionId, uint256 paymentCount, uint256 endsAt);
+    event PaymentFailed(uint256 indexed subscriptionId, uint256 required, uint256 available);
+    event SubscriptionStatusChanged(uint256 indexed subscriptionId, uint8 previousStatus, uint8 newStatus);
+}
+
+// Token calls made by the escrow
+sol! {
+    interface IERC20 {
+        function transfer(address to, uint256 amount) external returns (bool);
+        function transferFrom(address from, address to, uint256 amount) external returns (bool);
+        function allowance(address owner, address spender) external view returns (uint256);
+    }
+
+    interface IERC20Permit {
+        function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external;
+    }
+}
+
+// Automation entrypoints, used to build Gelato exec payloads
+sol! {
+    interface ISubscriptionAutomation {
+        function processSubscriptionPayment(uint256 subscriptionId) external returns (bool);
+        function processPaymentsBatch(uint256[] ids) external returns (uint256);
+    }
 }
 
-// Simplified error enum
-#[derive(SolidityError)]
+#[derive(SolidityError, Debug)]
 pub enum SubscriptionError {
     Unauthorized(Unauthorized),
-    InvalidInput(InvalidInput),
-    InsufficientFunds(InsufficientFunds),
-    NotFound(NotFound),
+    MissingRole(MissingRole),
+    AdminRoleTransferOnly(AdminRoleTransferOnly),
+    SignatureExpired(SignatureExpired),
+    InvalidSignature(InvalidSignature),
+    AlreadyInitialized(AlreadyInitialized),
+    ReentrantCall(ReentrantCall),
+    ZeroAddress(ZeroAddress),
+    ContractPaused(ContractPaused),
+    ContractNotPaused(ContractNotPaused),
+    ProviderAlreadyRegistered(ProviderAlreadyRegistered),
+    ProviderNotRegistered(ProviderNotRegistered),
+    NameTooLong(NameTooLong),
+    MetadataUriTooLong(MetadataUriTooLong),
+    InvalidPlanParameters(InvalidPlanParameters),
+    InvalidRefundPolicy(InvalidRefundPolicy),
+    GracePeriodTooLong(GracePeriodTooLong),
+    TrialTooLong(TrialTooLong),
+    PlanNotFound(PlanNotFound),
+    PlanInactive(PlanInactive),
+    PlanAlreadyActive(PlanAlreadyActive),
+    SubscriptionNotActive(SubscriptionNotActive),
+    PaymentNotDue(PaymentNotDue),
+    InvalidCycleCount(InvalidCycleCount),
+    InvalidBatchSize(InvalidBatchSize),
+    ZeroAmount(ZeroAmount),
+    InsufficientEscrow(InsufficientEscrow),
+    InsufficientTreasury(InsufficientTreasury),
+    NothingToWithdraw(NothingToWithdraw),
+    TransferFailed(TransferFailed),
+    TokenNotAllowed(TokenNotAllowed),
+    TokenPullFailed(TokenPullFailed),
+    AllowanceRequiresToken(AllowanceRequiresToken),
+    PermitFailed(PermitFailed),
+    PermitAmountTooLow(PermitAmountTooLow),
+    FeeTooHigh(FeeTooHigh),
+    InvalidFeeRange(InvalidFeeRange),
+    NoPendingFeeChange(NoPendingFeeChange),
+    FeeChangeNotReady(FeeChangeNotReady),
+    FeeDelayTooShort(FeeDelayTooShort),
+    KeeperAlreadyAdded(KeeperAlreadyAdded),
+    KeeperNotFound(KeeperNotFound),
+}
+
+// Refund applied to the unused part of the current interval on cancellation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RefundPolicy {
+    None = 0,
+    Prorated = 1,
+    CoolingOff = 2,
+}
+
+impl RefundPolicy {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RefundPolicy::None),
+            1 => Some(RefundPolicy::Prorated),
+            2 => Some(RefundPolicy::CoolingOff),
+            _ => None,
+        }
+    }
+}
+
+// Lifecycle of a subscription. Failed renewals move it to PastDue for the plan's grace
+// period; it returns to Active once a charge succeeds or is Suspended when grace runs out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SubscriptionStatus {
+    None = 0,
+    Active = 1,
+    PastDue = 2,
+    Suspended = 3,
+    Cancelled = 4,
+    Expired = 5,
+}
+
+impl SubscriptionStatus {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SubscriptionStatus::None),
+            1 => Some(SubscriptionStatus::Active),
+            2 => Some(SubscriptionStatus::PastDue),
+            3 => Some(SubscriptionStatus::Suspended),
+            4 => Some(SubscriptionStatus::Cancelled),
+            5 => Some(SubscriptionStatus::Expired),
+            _ => None,
+        }
+    }
+}
+
+// How a single charge is divided between the provider, the protocol and the keeper
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PaymentSplit {
+    pub provider_amount: U256,
+    pub protocol_fee: U256,
+    pub keeper_fee: U256,
 }
 
 // Main production contract storage
@@ -41,33 +333,93 @@ sol_storage! {
     #[entrypoint]
     pub struct SubscriptionEscrow {
         // Core admin controls
+        bool initialized;
         address admin;
+        address pending_admin;
+        address treasury;
         uint256 protocol_fee_percentage;
+        mapping(address => uint256) protocol_treasury_balance; // token => fees
+        uint256 pending_protocol_fee;
+        uint256 pending_protocol_fee_effective_at;
+        uint256 protocol_fee_change_delay;
+        
+        // Role membership for every privileged entrypoint; `admin` is the single ADMIN_ROLE holder
+        AccessControl access_control;
+        
+        // Emergency stop: guardians pause, only the admin unpauses
+        bool paused;
+        
+        // Payment automation
+        bool permissionless_processing;
+        uint256 keeper_fee_bps;
+        mapping(address => uint256) min_keeper_fee; // token => amount in that token's units
+        mapping(address => uint256) max_keeper_fee; // token => amount in that token's units
+        mapping(address => mapping(address => uint256)) keeper_earnings; // keeper => token => amount
+        
+        // Payment tokens; native ETH is always accepted
+        mapping(address => bool) allowed_tokens;
         
         // Counter state
         uint256 next_plan_id;
         uint256 next_subscription_id;
-        uint256 total_value_locked;
+        mapping(address => uint256) total_value_locked; // token => amount
         
         // Provider management
         mapping(address => bool) registered_providers;
-        mapping(address => uint256) provider_earnings;
+        mapping(address => bool) verified_providers;
+        mapping(address => string) provider_name;
+        mapping(address => string) provider_metadata_uri; // IPFS or HTTPS
+        mapping(address => address) provider_payout_address; // receives withdrawn earnings
+        mapping(address => uint256) provider_registered_at;
+        mapping(address => mapping(address => uint256)) provider_earnings; // provider => token => amount
         
         // Plan management  
         mapping(uint256 => address) plan_provider;
+        mapping(uint256 => address) plan_token;
         mapping(uint256 => uint256) plan_price;
         mapping(uint256 => uint256) plan_interval;
         mapping(uint256 => bool) plan_active;
+        mapping(uint256 => uint256) plan_pending_price;
+        mapping(uint256 => uint256) plan_pending_interval;
+        mapping(uint256 => uint256) plan_pending_effective_at;
+        mapping(uint256 => uint8) plan_refund_policy;
+        mapping(uint256 => uint256) plan_cooling_off_period;
+        mapping(uint256 => uint256) plan_grace_period;
+        mapping(uint256 => uint256) plan_max_cycles; // 0 = renews indefinitely
+        mapping(uint256 => uint256) plan_trial_duration;
+        mapping(uint256 => mapping(address => bool)) plan_trial_used;
         
         // Subscription management
         mapping(uint256 => uint256) subscription_plan_id;
         mapping(uint256 => address) subscription_subscriber;
         mapping(uint256 => uint256) subscription_created_at;
         mapping(uint256 => uint256) subscription_last_payment;
-        mapping(uint256 => bool) subscription_active;
+        mapping(uint256 => uint8) subscription_status;
+        mapping(uint256 => uint256) subscription_past_due_since;
+        mapping(uint256 => uint256) subscription_payment_count;
+        mapping(uint256 => uint256) subscription_max_cycles; // 0 = renews indefinitely
+        mapping(uint256 => bool) subscription_allowance_funded; // pull renewals via transferFrom
+        mapping(uint256 => uint256) subscription_last_provider_amount;
+        mapping(uint256 => uint256) subscription_next_due;
+        mapping(address => uint256[]) user_subscriptions;
+        
+        // Due-payment index: min-heap of active and past-due subscriptions ordered by next due time
+        uint256[] due_heap;
+        mapping(uint256 => uint256) due_heap_position; // 1-based, 0 = not queued
+        
+        // The same subscriptions bucketed by due hour, so checker_all can resume from a cursor
+        mapping(uint256 => uint256[]) due_buckets;
+        mapping(uint256 => uint256) due_bucket_of;
+        mapping(uint256 => uint256) due_bucket_slot; // 1-based, 0 = not bucketed
+        
+        // Meta-transaction replay protection
+        mapping(address => uint256) intent_nonces;
+        
+        // Reentrancy protection, held for the duration of any call that moves value
+        bool reentrancy_locked;
         
         // User financial management
-        mapping(address => uint256) user_escrow_balance;
+        mapping(address => mapping(address => uint256)) user_escrow_balance; // user => token => amount
     }
 }
 
@@ -76,18 +428,46 @@ impl SubscriptionEscrow {
     
     // ==================== INITIALIZATION ====================
     
-    pub fn initialize(&mut self) -> Result<bool, SubscriptionError> {
-        if self.admin.get() != Address::ZERO {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+    // Runs atomically with deployment through the StylusDeployer, so there is no window in
+    // which another account can initialize first. `keeper` may be zero to add none.
+    #[constructor]
+    pub fn constructor(
+        &mut self,
+        admin: Address,
+        treasury: Address,
+        protocol_fee_bps: U256,
+        keeper: Address,
+    ) -> Result<(), SubscriptionError> {
+        // The SDK already guards the constructor slot; a renounced admin is also zero, so
+        // initialization is tracked separately as well
+        if self.initialized.get() {
+            return Err(SubscriptionError::AlreadyInitialized(AlreadyInitialized {}));
+        }
+        if admin == Address::ZERO || treasury == Address::ZERO {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
+        }
+        if protocol_fee_bps > U256::from(MAX_PROTOCOL_FEE_BPS) {
+            return Err(SubscriptionError::FeeTooHigh(FeeTooHigh {
+                fee: protocol_fee_bps,
+                maxFee: U256::from(MAX_PROTOCOL_FEE_BPS)
+            }));
         }
         
-        let caller = self.vm().msg_sender();
-        self.admin.set(caller);
+        self.initialized.set(true);
+        self.admin.set(admin);
+        self.access_control.grant(ADMIN_ROLE, admin, admin);
+        self.access_control.grant(FEE_MANAGER_ROLE, admin, admin);
+        self.treasury.set(treasury);
         self.next_plan_id.set(U256::from(1));
         self.next_subscription_id.set(U256::from(1));
-        self.protocol_fee_percentage.set(U256::from(250)); // 2.5%
+        self.protocol_fee_percentage.set(protocol_fee_bps);
+        self.protocol_fee_change_delay.set(U256::from(DEFAULT_FEE_CHANGE_DELAY));
         
-        Ok(true)
+        if keeper != Address::ZERO {
+            self.access_control.grant(KEEPER_ROLE, keeper, admin);
+        }
+        
+        Ok(())
     }
     
     // ==================== PROVIDER FUNCTIONS ====================
@@ -96,273 +476,2028 @@ impl SubscriptionEscrow {
         let caller = self.vm().msg_sender();
         
         if self.registered_providers.get(caller) {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+            return Err(SubscriptionError::ProviderAlreadyRegistered(ProviderAlreadyRegistered { provider: caller }));
         }
         
-        if name.len() > 100 {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
-        }
+        Self::validate_profile(&name, "")?;
         
         self.registered_providers.insert(caller, true);
-        self.provider_earnings.insert(caller, U256::ZERO);
+        self.provider_name.setter(caller).set_str(&name);
+        self.provider_payout_address.insert(caller, caller);
+        self.provider_registered_at.insert(caller, U256::from(self.vm().block_timestamp()));
         
-        evm::log(ProviderRegistered { 
+        log(self.vm(), ProviderRegistered { 
             provider: caller, 
-            name: name 
+            name 
         });
         
         Ok(true)
     }
-    
-    pub fn create_plan(&mut self, price: U256, interval: U256) -> Result<U256, SubscriptionError> {
+
+    // Earnings are paid to `payout_address`, which may differ from the provider's signing key
+    pub fn update_provider_profile(
+        &mut self,
+        name: String,
+        metadata_uri: String,
+        payout_address: Address,
+    ) -> Result<bool, SubscriptionError> {
         let caller = self.vm().msg_sender();
         self.require_registered_provider(caller)?;
-        
-        if price.is_zero() || interval.is_zero() {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+        Self::validate_profile(&name, &metadata_uri)?;
+        if payout_address == Address::ZERO {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
         }
         
-        let plan_id = self.next_plan_id.get();
-        
-        // Store plan data
-        self.plan_provider.insert(plan_id, caller);
-        self.plan_price.insert(plan_id, price);
-        self.plan_interval.insert(plan_id, interval);
-        self.plan_active.insert(plan_id, true);
-        
-        // Update counters
-        self.next_plan_id.set(plan_id + U256::from(1));
+        self.provider_name.setter(caller).set_str(&name);
+        self.provider_metadata_uri.setter(caller).set_str(&metadata_uri);
+        self.provider_payout_address.insert(caller, payout_address);
         
-        evm::log(PlanCreated {
-            planId: plan_id,
+        log(self.vm(), ProviderProfileUpdated {
             provider: caller,
-            price: price,
-            interval: interval
+            name,
+            metadataUri: metadata_uri,
+            payoutAddress: payout_address
         });
         
-        Ok(plan_id)
+        Ok(true)
     }
     
-    // ==================== SUBSCRIPTION FUNCTIONS ====================
+    // Verification is an off-chain review marker; it does not gate plans or payments
+    pub fn set_provider_verified(&mut self, provider: Address, verified: bool) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.access_control.check_role(PROVIDER_VERIFIER_ROLE, caller)?;
+        self.require_registered_provider(provider)?;
+
+        self.verified_providers.insert(provider, verified);
+        log(self.vm(), ProviderVerificationUpdated { provider, verified });
+
+        Ok(true)
+    }
     
-    #[payable]
-    pub fn subscribe(&mut self, plan_id: U256) -> Result<U256, SubscriptionError> {
+    pub fn create_plan(&mut self, price: U256, interval: U256) -> Result<U256, SubscriptionError> {
+        self.create_plan_in(NATIVE_TOKEN, price, interval)
+    }
+
+    pub fn create_token_plan(&mut self, token: Address, price: U256, interval: U256) -> Result<U256, SubscriptionError> {
+        self.require_allowed_token(token)?;
+        self.create_plan_in(token, price, interval)
+    }
+    
+    pub fn update_plan(&mut self, plan_id: U256, price: U256, interval: U256) -> Result<U256, SubscriptionError> {
         let caller = self.vm().msg_sender();
-        let payment = self.vm().msg_value();
+        self.require_plan_provider(plan_id, caller)?;
         
-        // Validate plan exists and is active
-        let plan_provider = self.plan_provider.get(plan_id);
-        if plan_provider == Address::ZERO {
-            return Err(SubscriptionError::NotFound(NotFound {}));
+        if price.is_zero() || interval.is_zero() || interval > U256::from(MAX_PLAN_INTERVAL) {
+            return Err(SubscriptionError::InvalidPlanParameters(InvalidPlanParameters { price, interval }));
         }
         
-        if !self.plan_active.get(plan_id) {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
-        }
+        // Staged so existing subscribers are only charged the new terms after the notice period
+        let (current_price, _) = self.sync_plan_terms(plan_id);
+        let effective_at = U256::from(self.vm().block_timestamp()) + U256::from(PLAN_CHANGE_NOTICE_PERIOD);
+        self.plan_pending_price.insert(plan_id, price);
+        self.plan_pending_interval.insert(plan_id, interval);
+        self.plan_pending_effective_at.insert(plan_id, effective_at);
         
-        let plan_price = self.plan_price.get(plan_id);
+        log(self.vm(), PlanPriceChangeScheduled {
+            planId: plan_id,
+            currentPrice: current_price,
+            newPrice: price,
+            newInterval: interval,
+            effectiveAt: effective_at
+        });
         
-        // Handle payment deposit
-        if payment > U256::ZERO {
-            self.process_deposit(caller, payment)?;
-        }
+        Ok(effective_at)
+    }
+
+    pub fn deactivate_plan(&mut self, plan_id: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_plan_provider(plan_id, caller)?;
         
-        // Check sufficient balance
-        let user_balance = self.user_escrow_balance.get(caller);
-        if user_balance < plan_price {
-            return Err(SubscriptionError::InsufficientFunds(InsufficientFunds {}));
+        if !self.plan_active.get(plan_id) {
+            return Err(SubscriptionError::PlanInactive(PlanInactive { planId: plan_id }));
         }
         
-       
-        let protocol_fee = (plan_price * self.protocol_fee_percentage.get()) / U256::from(10000);
-        let provider_amount = plan_price - protocol_fee;
-        
-       
-        let subscription_id = self.next_subscription_id.get();
-        let current_time = U256::from(self.vm().block_timestamp());
-        
-        self.subscription_plan_id.insert(subscription_id, plan_id);
-        self.subscription_subscriber.insert(subscription_id, caller);
-        self.subscription_created_at.insert(subscription_id, current_time);
-        self.subscription_last_payment.insert(subscription_id, current_time);
-        self.subscription_active.insert(subscription_id, true);
-        
-     
-        self.user_escrow_balance.insert(caller, user_balance - plan_price);
-        
-        let provider_earnings = self.provider_earnings.get(plan_provider);
-        self.provider_earnings.insert(plan_provider, provider_earnings + provider_amount);
+        // Only new subscriptions are blocked; existing ones keep renewing
+        self.plan_active.insert(plan_id, false);
+        log(self.vm(), PlanDeactivated { planId: plan_id });
         
-       
-        self.next_subscription_id.set(subscription_id + U256::from(1));
+        Ok(true)
+    }
+
+    pub fn reactivate_plan(&mut self, plan_id: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_plan_provider(plan_id, caller)?;
         
-        evm::log(SubscriptionCreated {
-            subscriptionId: subscription_id,
-            user: caller,
-            planId: plan_id
-        });
+        if self.plan_active.get(plan_id) {
+            return Err(SubscriptionError::PlanAlreadyActive(PlanAlreadyActive { planId: plan_id }));
+        }
         
-        evm::log(PaymentProcessed {
-            from: caller,
-            to: plan_provider,
-            amount: provider_amount
-        });
+        self.plan_active.insert(plan_id, true);
+        log(self.vm(), PlanReactivated { planId: plan_id });
         
-        Ok(subscription_id)
+        Ok(true)
     }
-    
-    // ==================== FINANCIAL FUNCTIONS ====================
-    
-    #[payable]
-    pub fn deposit(&mut self) -> Result<bool, SubscriptionError> {
+
+    pub fn set_plan_refund_policy(
+        &mut self,
+        plan_id: U256,
+        policy: u8,
+        cooling_off_period: U256,
+    ) -> Result<bool, SubscriptionError> {
         let caller = self.vm().msg_sender();
-        let amount = self.vm().msg_value();
-        
-        self.process_deposit(caller, amount)?;
+        self.require_plan_provider(plan_id, caller)?;
+
+        if RefundPolicy::from_u8(policy).is_none() {
+            return Err(SubscriptionError::InvalidRefundPolicy(InvalidRefundPolicy { policy }));
+        }
+
+        self.plan_refund_policy.insert(plan_id, U8::from(policy));
+        self.plan_cooling_off_period.insert(plan_id, cooling_off_period);
+
+        log(self.vm(), PlanRefundPolicyUpdated {
+            planId: plan_id,
+            policy,
+            coolingOffPeriod: cooling_off_period
+        });
+
         Ok(true)
     }
-    
-    // ==================== WITHDRAWAL FUNCTIONS ====================
-    
-    pub fn withdraw_provider_earnings(&mut self) -> Result<bool, SubscriptionError> {
-        let provider = self.vm().msg_sender();
-        self.require_registered_provider(provider)?;
-        
-        let earnings = self.provider_earnings.get(provider);
-        if earnings.is_zero() {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+
+    pub fn set_plan_grace_period(&mut self, plan_id: U256, grace_period: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_plan_provider(plan_id, caller)?;
+
+        if grace_period > U256::from(MAX_GRACE_PERIOD) {
+            return Err(SubscriptionError::GracePeriodTooLong(GracePeriodTooLong {
+                gracePeriod: grace_period,
+                maxGracePeriod: U256::from(MAX_GRACE_PERIOD)
+            }));
         }
-        
-        self.provider_earnings.insert(provider, U256::ZERO);
-        let total_locked = self.total_value_locked.get();
-        self.total_value_locked.set(total_locked - earnings);
-        
-        match self.vm().transfer_eth(provider, earnings) {
-            Ok(()) => {
-                evm::log(EarningsWithdrawn { provider, amount: earnings });
-                Ok(true)
-            },
-            Err(_) => {
-                self.provider_earnings.insert(provider, earnings);
-                self.total_value_locked.set(total_locked);
-                Err(SubscriptionError::InvalidInput(InvalidInput {}))
-            }
+
+        self.plan_grace_period.insert(plan_id, grace_period);
+        log(self.vm(), PlanGracePeriodUpdated { planId: plan_id, gracePeriod: grace_period });
+
+        Ok(true)
+    }
+
+    pub fn set_plan_trial_duration(&mut self, plan_id: U256, trial_duration: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_plan_provider(plan_id, caller)?;
+
+        if trial_duration > U256::from(MAX_TRIAL_DURATION) {
+            return Err(SubscriptionError::TrialTooLong(TrialTooLong {
+                duration: trial_duration,
+                maxDuration: U256::from(MAX_TRIAL_DURATION)
+            }));
         }
+
+        self.plan_trial_duration.insert(plan_id, trial_duration);
+        log(self.vm(), PlanTrialDurationUpdated { planId: plan_id, trialDuration: trial_duration });
+
+        Ok(true)
+    }
+
+    // Applies to subscriptions created afterwards; existing ones keep the limit they started with
+    pub fn set_plan_max_cycles(&mut self, plan_id: U256, max_cycles: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_plan_provider(plan_id, caller)?;
+
+        self.plan_max_cycles.insert(plan_id, max_cycles);
+        log(self.vm(), PlanMaxCyclesUpdated { planId: plan_id, maxCycles: max_cycles });
+
+        Ok(true)
     }
     
-    // ==================== GELATO AUTOMATION ====================
+    // ==================== SUBSCRIPTION FUNCTIONS ====================
     
-    pub fn checker(&self, subscriber: Address) -> (bool, Bytes) {
-        let mut subscription_id = U256::from(1);
-        let max_subscriptions = self.next_subscription_id.get();
-        
-        while subscription_id < max_subscriptions {
-            if self.subscription_subscriber.get(subscription_id) == subscriber 
-                && self.subscription_active.get(subscription_id) {
-                
-                let plan_id = self.subscription_plan_id.get(subscription_id);
-                let plan_price = self.plan_price.get(plan_id);
-                let plan_interval = self.plan_interval.get(plan_id);
-                let last_payment = self.subscription_last_payment.get(subscription_id);
-                let user_balance = self.user_escrow_balance.get(subscriber);
-                let current_time = U256::from(self.vm().block_timestamp());
-                
-                if current_time >= last_payment + plan_interval && user_balance >= plan_price {
-                    let mut exec_payload = Vec::new();
-                    exec_payload.extend_from_slice(&[0x8d, 0x96, 0x7d, 0x8b]);
-                    let id_bytes = subscription_id.to_be_bytes::<32>();
-                    exec_payload.extend_from_slice(&id_bytes);
-                    return (true, Bytes::from(exec_payload));
-                }
-            }
-            subscription_id += U256::from(1);
-        }
-        
-        (false, Bytes::from(Vec::<u8>::new()))
+    #[payable]
+    pub fn subscribe(&mut self, plan_id: U256) -> Result<U256, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.non_reentrant(|this| this.create_subscription(caller, plan_id, U256::ZERO, false))
     }
-    
-    pub fn process_subscription_payment(&mut self, subscription_id: U256) -> Result<bool, SubscriptionError> {
+
+    // Funds renewals from the subscriber's wallet through an ERC-20 allowance instead of escrow
+    pub fn subscribe_with_allowance(&mut self, plan_id: U256) -> Result<U256, SubscriptionError> {
         let caller = self.vm().msg_sender();
-        
-        if caller != self.admin.get() {
-            return Err(SubscriptionError::Unauthorized(Unauthorized {}));
+        self.non_reentrant(|this| this.create_subscription(caller, plan_id, U256::ZERO, true))
+    }
+
+    // Fixed-term package: the subscription expires after `max_cycles` charges
+    #[payable]
+    pub fn subscribe_for_cycles(&mut self, plan_id: U256, max_cycles: U256) -> Result<U256, SubscriptionError> {
+        if max_cycles.is_zero() {
+            return Err(SubscriptionError::InvalidCycleCount(InvalidCycleCount {
+                cycles: max_cycles,
+                maxCycles: self.plan_max_cycles.get(plan_id)
+            }));
         }
-        
-        if !self.subscription_active.get(subscription_id) {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+        let caller = self.vm().msg_sender();
+        self.non_reentrant(|this| this.create_subscription(caller, plan_id, max_cycles, false))
+    }
+
+    // Approve, prefund and subscribe in one call. `amount` covers the first charge plus any
+    // extra escrow prefund and is pulled in full.
+    pub fn subscribe_with_permit(
+        &mut self,
+        plan_id: U256,
+        amount: U256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256,
+    ) -> Result<U256, SubscriptionError> {
+        self.non_reentrant(|this| {
+            this.require_not_paused()?;
+            let caller = this.vm().msg_sender();
+            let token = this.plan_token.get(plan_id);
+            if this.plan_provider.get(plan_id) == Address::ZERO {
+                return Err(SubscriptionError::PlanNotFound(PlanNotFound { planId: plan_id }));
+            }
+            if token == NATIVE_TOKEN {
+                return Err(SubscriptionError::AllowanceRequiresToken(AllowanceRequiresToken { planId: plan_id }));
+            }
+            this.require_allowed_token(token)?;
+
+            let (plan_price, _) = this.sync_plan_terms(plan_id);
+            if amount < plan_price {
+                return Err(SubscriptionError::PermitAmountTooLow(PermitAmountTooLow { amount, required: plan_price }));
+            }
+
+            let spender = this.vm().contract_address();
+            let permit = IERC20Permit::permitCall { owner: caller, spender, value: amount, deadline, v, r, s };
+            // A front-run permit consumes the nonce, so fall back to an allowance that already covers the pull
+            if this.vm().call(&Call::new(), token, &permit.abi_encode()).is_err()
+                && this.token_allowance(token, caller, spender) < amount
+            {
+                return Err(SubscriptionError::PermitFailed(PermitFailed { token, owner: caller }));
+            }
+            if !this.pull_into_escrow(caller, token, amount) {
+                return Err(SubscriptionError::TokenPullFailed(TokenPullFailed { token, from: caller, amount }));
+            }
+            log(this.vm(), TokenDeposited { user: caller, token, amount });
+
+            this.create_subscription(caller, plan_id, U256::ZERO, false)
+        })
+    }
+
+    pub fn set_allowance_funding(&mut self, subscription_id: U256, enabled: bool) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        if caller != self.subscription_subscriber.get(subscription_id) {
+            return Err(SubscriptionError::Unauthorized(Unauthorized { caller }));
         }
-        
-        let subscriber = self.subscription_subscriber.get(subscription_id);
+
         let plan_id = self.subscription_plan_id.get(subscription_id);
-        let plan_provider = self.plan_provider.get(plan_id);
-        let plan_price = self.plan_price.get(plan_id);
-        let plan_interval = self.plan_interval.get(plan_id);
-        
-        let last_payment = self.subscription_last_payment.get(subscription_id);
-        let current_time = U256::from(self.vm().block_timestamp());
-        
-        if current_time < last_payment + plan_interval {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
-        }
-        
-        let user_balance = self.user_escrow_balance.get(subscriber);
-        if user_balance < plan_price {
-            self.subscription_active.insert(subscription_id, false);
-            return Err(SubscriptionError::InsufficientFunds(InsufficientFunds {}));
+        if enabled && self.plan_token.get(plan_id) == NATIVE_TOKEN {
+            return Err(SubscriptionError::AllowanceRequiresToken(AllowanceRequiresToken { planId: plan_id }));
         }
-        
-        let protocol_fee = (plan_price * self.protocol_fee_percentage.get()) / U256::from(10000);
-        let provider_amount = plan_price - protocol_fee;
-        
-        self.user_escrow_balance.insert(subscriber, user_balance - plan_price);
-        let provider_earnings = self.provider_earnings.get(plan_provider);
-        self.provider_earnings.insert(plan_provider, provider_earnings + provider_amount);
-        self.subscription_last_payment.insert(subscription_id, current_time);
-        
-        evm::log(PaymentProcessed { from: subscriber, to: plan_provider, amount: provider_amount });
+
+        self.subscription_allowance_funded.insert(subscription_id, enabled);
+        log(self.vm(), AllowanceFundingUpdated { subscriptionId: subscription_id, enabled });
+
         Ok(true)
     }
-    
-    // ==================== VIEW FUNCTIONS ====================
-    
-    pub fn get_admin(&self) -> Address {
-        self.admin.get()
+
+    pub fn cancel_subscription(&mut self, subscription_id: U256) -> Result<U256, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.non_reentrant(|this| this.cancel_subscription_as(caller, subscription_id))
     }
     
-    pub fn get_user_balance(&self, user: Address) -> U256 {
-        self.user_escrow_balance.get(user)
-    }
+    // ==================== FINANCIAL FUNCTIONS ====================
     
-    pub fn get_provider_earnings(&self, provider: Address) -> U256 {
-        self.provider_earnings.get(provider)
+    #[payable]
+    pub fn deposit(&mut self) -> Result<bool, SubscriptionError> {
+        self.non_reentrant(|this| {
+            let caller = this.vm().msg_sender();
+            let amount = this.vm().msg_value();
+        
+            this.process_deposit(caller, NATIVE_TOKEN, amount)?;
+            this.recover_past_due(caller);
+            Ok(true)
+        })
     }
-    
-    pub fn is_provider_registered(&self, provider: Address) -> bool {
-        self.registered_providers.get(provider)
+
+    pub fn deposit_token(&mut self, token: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        self.non_reentrant(|this| {
+            let caller = this.vm().msg_sender();
+            if token == NATIVE_TOKEN {
+                return Err(SubscriptionError::TokenNotAllowed(TokenNotAllowed { token }));
+            }
+            this.require_allowed_token(token)?;
+            if amount.is_zero() {
+                return Err(SubscriptionError::ZeroAmount(ZeroAmount {}));
+            }
+        
+            if !this.pull_into_escrow(caller, token, amount) {
+                return Err(SubscriptionError::TokenPullFailed(TokenPullFailed { token, from: caller, amount }));
+            }
+            log(this.vm(), TokenDeposited { user: caller, token, amount });
+        
+            this.recover_past_due(caller);
+            Ok(true)
+        })
     }
     
-    // ==================== INTERNAL HELPER FUNCTIONS ====================
+    // ==================== WITHDRAWAL FUNCTIONS ====================
     
-    fn require_registered_provider(&self, provider: Address) -> Result<(), SubscriptionError> {
-        if !self.registered_providers.get(provider) {
-            return Err(SubscriptionError::Unauthorized(Unauthorized {}));
-        }
-        Ok(())
+    pub fn withdraw_provider_earnings(&mut self) -> Result<bool, SubscriptionError> {
+        self.withdraw_provider_token_earnings(NATIVE_TOKEN)
+    }
+
+    pub fn withdraw_provider_token_earnings(&mut self, token: Address) -> Result<bool, SubscriptionError> {
+        let provider = self.vm().msg_sender();
+        self.non_reentrant(|this| this.withdraw_provider_earnings_as(provider, token))
+    }
+
+    pub fn withdraw_escrow(&mut self, amount: U256) -> Result<bool, SubscriptionError> {
+        self.withdraw_token_escrow(NATIVE_TOKEN, amount)
+    }
+
+    pub fn withdraw_token_escrow(&mut self, token: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        let user = self.vm().msg_sender();
+        self.non_reentrant(|this| this.withdraw_escrow_as(user, token, amount))
+    }
+
+    pub fn withdraw_protocol_fees(&mut self, to: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        self.withdraw_protocol_token_fees(NATIVE_TOKEN, to, amount)
+    }
+
+    pub fn withdraw_protocol_token_fees(&mut self, token: Address, to: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+        self.non_reentrant(|this| this.withdraw_protocol_fees_to(token, to, amount))
+    }
+
+    pub fn withdraw_keeper_earnings(&mut self) -> Result<bool, SubscriptionError> {
+        self.withdraw_keeper_token_earnings(NATIVE_TOKEN)
+    }
+
+    pub fn withdraw_keeper_token_earnings(&mut self, token: Address) -> Result<bool, SubscriptionError> {
+        let keeper = self.vm().msg_sender();
+        self.non_reentrant(|this| this.withdraw_keeper_earnings_as(keeper, token))
+    }
+
+    // ==================== META-TRANSACTIONS ====================
+    
+    // Relayed calls act on behalf of the signer of an EIP-712 intent; the relayer pays gas
+
+    pub fn subscribe_by_sig(
+        &mut self,
+        subscriber: Address,
+        plan_id: U256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256,
+    ) -> Result<U256, SubscriptionError> {
+        let nonce = self.intent_nonces.get(subscriber);
+        let struct_hash = keccak((B256::from(SUBSCRIBE_TYPEHASH), subscriber, plan_id, nonce, deadline).abi_encode());
+        self.non_reentrant(|this| {
+            this.verify_intent(subscriber, struct_hash, deadline, v, r, s)?;
+            this.create_subscription(subscriber, plan_id, U256::ZERO, false)
+        })
+    }
+
+    pub fn cancel_subscription_by_sig(
+        &mut self,
+        subscriber: Address,
+        subscription_id: U256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256,
+    ) -> Result<U256, SubscriptionError> {
+        let nonce = self.intent_nonces.get(subscriber);
+        let struct_hash = keccak((B256::from(CANCEL_TYPEHASH), subscriber, subscription_id, nonce, deadline).abi_encode());
+        self.non_reentrant(|this| {
+            this.verify_intent(subscriber, struct_hash, deadline, v, r, s)?;
+            this.cancel_subscription_as(subscriber, subscription_id)
+        })
+    }
+
+    // Funds always go to the signer, never to the relayer
+    #[allow(clippy::too_many_arguments)]
+    pub fn withdraw_escrow_by_sig(
+        &mut self,
+        user: Address,
+        token: Address,
+        amount: U256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256,
+    ) -> Result<bool, SubscriptionError> {
+        let nonce = self.intent_nonces.get(user);
+        let struct_hash = keccak((B256::from(WITHDRAW_ESCROW_TYPEHASH), user, token, amount, nonce, deadline).abi_encode());
+        self.non_reentrant(|this| {
+            this.verify_intent(user, struct_hash, deadline, v, r, s)?;
+            this.withdraw_escrow_as(user, token, amount)
+        })
+    }
+
+    // ==================== ACCESS CONTROL ====================
+
+    pub fn has_role(&self, role: B256, account: Address) -> bool {
+        self.access_control.has_role(role, account)
+    }
+
+    pub fn get_role_admin(&self, role: B256) -> B256 {
+        self.access_control.role_admin(role)
+    }
+
+    // ADMIN_ROLE has a single holder and only moves through the admin transfer flow
+    pub fn grant_role(&mut self, role: B256, account: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        if role == ADMIN_ROLE {
+            return Err(SubscriptionError::AdminRoleTransferOnly(AdminRoleTransferOnly {}));
+        }
+        self.require_role_admin(role, caller)?;
+        if account == Address::ZERO {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
+        }
+
+        Ok(self.access_control.grant(role, account, caller))
+    }
+
+    pub fn revoke_role(&mut self, role: B256, account: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        if role == ADMIN_ROLE {
+            return Err(SubscriptionError::AdminRoleTransferOnly(AdminRoleTransferOnly {}));
+        }
+        self.require_role_admin(role, caller)?;
+
+        Ok(self.access_control.revoke(role, account, caller))
+    }
+
+    pub fn renounce_role(&mut self, role: B256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        if role == ADMIN_ROLE {
+            return Err(SubscriptionError::AdminRoleTransferOnly(AdminRoleTransferOnly {}));
+        }
+
+        Ok(self.access_control.revoke(role, caller, caller))
+    }
+
+    pub fn set_role_admin(&mut self, role: B256, admin_role: B256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+        if role == ADMIN_ROLE {
+            return Err(SubscriptionError::AdminRoleTransferOnly(AdminRoleTransferOnly {}));
+        }
+
+        self.access_control.set_role_admin(role, admin_role);
+        Ok(true)
+    }
+
+    // ==================== ADMIN FUNCTIONS ====================
+
+    // Starts a handover that the new admin must accept; a zero address cancels it
+    pub fn transfer_admin(&mut self, new_admin: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        self.pending_admin.set(new_admin);
+        log(self.vm(), AdminTransferStarted { currentAdmin: caller, pendingAdmin: new_admin });
+
+        Ok(true)
+    }
+
+    pub fn accept_admin(&mut self) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        let pending_admin = self.pending_admin.get();
+        if pending_admin == Address::ZERO || caller != pending_admin {
+            return Err(SubscriptionError::Unauthorized(Unauthorized { caller }));
+        }
+
+        let previous_admin = self.admin.get();
+        self.access_control.revoke(ADMIN_ROLE, previous_admin, caller);
+        self.access_control.grant(ADMIN_ROLE, caller, caller);
+        // The fee-manager seat granted at deployment travels with the admin
+        if self.access_control.revoke(FEE_MANAGER_ROLE, previous_admin, caller) {
+            self.access_control.grant(FEE_MANAGER_ROLE, caller, caller);
+        }
+        self.admin.set(caller);
+        self.pending_admin.set(Address::ZERO);
+        log(self.vm(), AdminTransferred { previousAdmin: previous_admin, newAdmin: caller });
+
+        Ok(true)
+    }
+
+    // Permanently gives up admin control: roles, treasury and unpausing can no longer change
+    pub fn renounce_admin(&mut self) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        self.access_control.revoke(ADMIN_ROLE, caller, caller);
+        self.access_control.revoke(FEE_MANAGER_ROLE, caller, caller);
+        self.admin.set(Address::ZERO);
+        self.pending_admin.set(Address::ZERO);
+        log(self.vm(), AdminRenounced { previousAdmin: caller });
+
+        Ok(true)
+    }
+
+    pub fn set_treasury(&mut self, new_treasury: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        if new_treasury == Address::ZERO {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
+        }
+
+        let previous_treasury = self.treasury.get();
+        self.treasury.set(new_treasury);
+
+        log(self.vm(), TreasuryUpdated {
+            previousTreasury: previous_treasury,
+            newTreasury: new_treasury
+        });
+
+        Ok(true)
+    }
+
+    pub fn set_token_allowed(&mut self, token: Address, allowed: bool) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        if token == NATIVE_TOKEN {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
+        }
+
+        self.allowed_tokens.insert(token, allowed);
+        log(self.vm(), PaymentTokenUpdated { token, allowed });
+
+        Ok(true)
+    }
+
+    pub fn set_protocol_fee(&mut self, fee_bps: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.access_control.check_role(FEE_MANAGER_ROLE, caller)?;
+
+        if fee_bps > U256::from(MAX_PROTOCOL_FEE_BPS) {
+            return Err(SubscriptionError::FeeTooHigh(FeeTooHigh {
+                fee: fee_bps,
+                maxFee: U256::from(MAX_PROTOCOL_FEE_BPS)
+            }));
+        }
+
+        // Staged so subscribers can exit before a new fee applies to them
+        let current_fee = self.sync_protocol_fee();
+        let effective_at = U256::from(self.vm().block_timestamp()) + self.protocol_fee_change_delay.get();
+        self.pending_protocol_fee.set(fee_bps);
+        self.pending_protocol_fee_effective_at.set(effective_at);
+
+        log(self.vm(), ProtocolFeeChangeScheduled {
+            currentFee: current_fee,
+            newFee: fee_bps,
+            effectiveAt: effective_at
+        });
+
+        Ok(true)
+    }
+
+    // Stays with the admin so fee managers cannot shorten their own timelock
+    pub fn set_protocol_fee_delay(&mut self, delay: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        // A floor keeps even the admin from turning the timelock into an instant change
+        if delay < U256::from(MIN_FEE_CHANGE_DELAY) {
+            return Err(SubscriptionError::FeeDelayTooShort(FeeDelayTooShort {
+                delay,
+                minDelay: U256::from(MIN_FEE_CHANGE_DELAY)
+            }));
+        }
+
+        self.protocol_fee_change_delay.set(delay);
+        log(self.vm(), ProtocolFeeDelayUpdated { delay });
+
+        Ok(true)
+    }
+
+    pub fn apply_protocol_fee_change(&mut self) -> Result<U256, SubscriptionError> {
+        let effective_at = self.pending_protocol_fee_effective_at.get();
+        if effective_at.is_zero() {
+            return Err(SubscriptionError::NoPendingFeeChange(NoPendingFeeChange {}));
+        }
+        if U256::from(self.vm().block_timestamp()) < effective_at {
+            return Err(SubscriptionError::FeeChangeNotReady(FeeChangeNotReady { effectiveAt: effective_at }));
+        }
+
+        Ok(self.sync_protocol_fee())
+    }
+
+    // Shorthands for granting and revoking KEEPER_ROLE that reject no-op changes
+    pub fn add_keeper(&mut self, keeper: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_role_admin(KEEPER_ROLE, caller)?;
+
+        if keeper == Address::ZERO {
+            return Err(SubscriptionError::ZeroAddress(ZeroAddress {}));
+        }
+        if !self.access_control.grant(KEEPER_ROLE, keeper, caller) {
+            return Err(SubscriptionError::KeeperAlreadyAdded(KeeperAlreadyAdded { keeper }));
+        }
+
+        Ok(true)
+    }
+
+    pub fn remove_keeper(&mut self, keeper: Address) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_role_admin(KEEPER_ROLE, caller)?;
+
+        if !self.access_control.revoke(KEEPER_ROLE, keeper, caller) {
+            return Err(SubscriptionError::KeeperNotFound(KeeperNotFound { keeper }));
+        }
+
+        Ok(true)
+    }
+
+    pub fn set_permissionless_processing(&mut self, enabled: bool) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        self.permissionless_processing.set(enabled);
+        log(self.vm(), PermissionlessProcessingUpdated { enabled });
+
+        Ok(true)
+    }
+
+    pub fn set_keeper_fee(
+        &mut self,
+        fee_bps: U256,
+        min_fee: U256,
+        max_fee: U256,
+    ) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.access_control.check_role(FEE_MANAGER_ROLE, caller)?;
+
+        if fee_bps > U256::from(MAX_KEEPER_FEE_BPS) {
+            return Err(SubscriptionError::FeeTooHigh(FeeTooHigh {
+                fee: fee_bps,
+                maxFee: U256::from(MAX_KEEPER_FEE_BPS)
+            }));
+        }
+        if min_fee > max_fee {
+            return Err(SubscriptionError::InvalidFeeRange(InvalidFeeRange { minFee: min_fee, maxFee: max_fee }));
+        }
+
+        self.keeper_fee_bps.set(fee_bps);
+        self.min_keeper_fee.insert(NATIVE_TOKEN, min_fee);
+        self.max_keeper_fee.insert(NATIVE_TOKEN, max_fee);
+
+        log(self.vm(), KeeperFeeUpdated {
+            feeBps: fee_bps,
+            minFee: min_fee,
+            maxFee: max_fee
+        });
+
+        Ok(true)
+    }
+
+    // Keeper fee clamps for a payment token, in its own units; an unset token pays keepers nothing
+    pub fn set_token_keeper_fee(
+        &mut self,
+        token: Address,
+        min_fee: U256,
+        max_fee: U256,
+    ) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.access_control.check_role(FEE_MANAGER_ROLE, caller)?;
+        self.require_allowed_token(token)?;
+
+        if min_fee > max_fee {
+            return Err(SubscriptionError::InvalidFeeRange(InvalidFeeRange { minFee: min_fee, maxFee: max_fee }));
+        }
+
+        self.min_keeper_fee.insert(token, min_fee);
+        self.max_keeper_fee.insert(token, max_fee);
+
+        log(self.vm(), TokenKeeperFeeUpdated {
+            token,
+            minFee: min_fee,
+            maxFee: max_fee
+        });
+
+        Ok(true)
+    }
+
+    // Stops new subscriptions, renewals and earnings payouts. Escrow withdrawals and
+    // cancellations stay open so subscribers can always exit.
+    pub fn pause(&mut self) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.access_control.check_role(GUARDIAN_ROLE, caller)?;
+        self.require_not_paused()?;
+
+        self.paused.set(true);
+        log(self.vm(), Paused { by: caller });
+
+        Ok(true)
+    }
+
+    pub fn unpause(&mut self) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_admin(caller)?;
+
+        if !self.paused.get() {
+            return Err(SubscriptionError::ContractNotPaused(ContractNotPaused {}));
+        }
+
+        self.paused.set(false);
+        log(self.vm(), Unpaused { by: caller });
+
+        Ok(true)
+    }
+
+    // ==================== GELATO AUTOMATION ====================
+    
+    pub fn checker(&self, subscriber: Address) -> (bool, Bytes) {
+        if self.paused.get() {
+            return (false, Bytes::from(Vec::<u8>::new()));
+        }
+        
+        let subscriptions = self.user_subscriptions.get(subscriber);
+        let current_time = U256::from(self.vm().block_timestamp());
+        
+        for index in 0..subscriptions.len() {
+            let subscription_id = subscriptions.get(index).unwrap_or_default();
+            if !self.is_renewal_tracked(subscription_id) {
+                continue;
+            }
+            
+            // Unfunded renewals are still worth executing: they advance the dunning state
+            let next_due = self.subscription_next_due.get(subscription_id);
+            if current_time >= next_due {
+                return (true, Bytes::from(Self::single_payment_payload(subscription_id)));
+            }
+        }
+        
+        (false, Bytes::from(Vec::<u8>::new()))
+    }
+
+    pub fn checker_all(&self, cursor: U256, max_results: U256) -> (bool, Bytes, U256) {
+        if self.paused.get() {
+            return (false, Bytes::from(Vec::<u8>::new()), cursor);
+        }
+        
+        let limit = max_results.saturating_to::<usize>().min(MAX_BATCH_SIZE);
+        let (ids, next_cursor) = self.collect_due_page(cursor, limit);
+        
+        let exec_payload = match ids.len() {
+            0 => Vec::new(),
+            1 => Self::single_payment_payload(ids[0]),
+            _ => Self::batch_payment_payload(&ids),
+        };
+        
+        (!ids.is_empty(), Bytes::from(exec_payload), next_cursor)
+    }
+
+    pub fn next_due_subscriptions(&self, limit: U256) -> Vec<U256> {
+        let limit = limit.saturating_to::<usize>().min(MAX_DUE_QUERY);
+        self.collect_due(limit)
+    }
+    
+    pub fn process_subscription_payment(&mut self, subscription_id: U256) -> Result<bool, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        
+        self.require_keeper(caller)?;
+        self.require_not_paused()?;
+        self.non_reentrant(|this| this.process_payment(subscription_id, caller))
+    }
+
+    pub fn process_payments_batch(&mut self, ids: Vec<U256>) -> Result<U256, SubscriptionError> {
+        self.non_reentrant(|this| {
+            let caller = this.vm().msg_sender();
+            this.require_keeper(caller)?;
+            this.require_not_paused()?;
+
+            if ids.is_empty() || ids.len() > MAX_BATCH_SIZE {
+                return Err(SubscriptionError::InvalidBatchSize(InvalidBatchSize {
+                    size: U256::from(ids.len()),
+                    maxSize: U256::from(MAX_BATCH_SIZE)
+                }));
+            }
+
+            // Each id succeeds or is skipped on its own instead of reverting the whole batch;
+            // a bit is only set when the renewal was actually charged
+            let mut status_bitmap = U256::ZERO;
+            let mut processed = 0u64;
+            for (index, subscription_id) in ids.iter().enumerate() {
+                if let Ok(true) = this.process_payment(*subscription_id, caller) {
+                    status_bitmap.set_bit(index, true);
+                    processed += 1;
+                }
+            }
+
+            log(this.vm(), BatchPaymentsProcessed {
+                keeper: caller,
+                processed: U256::from(processed),
+                skipped: U256::from(ids.len() as u64 - processed),
+                statusBitmap: status_bitmap
+            });
+
+            Ok(status_bitmap)
+        })
+    }
+    
+    // ==================== VIEW FUNCTIONS ====================
+    
+    pub fn get_nonce(&self, account: Address) -> U256 {
+        self.intent_nonces.get(account)
+    }
+
+    pub fn get_domain_separator(&self) -> B256 {
+        utils::domain_separator(EIP712_NAME, EIP712_VERSION, self.vm().chain_id(), self.vm().contract_address())
+    }
+
+    pub fn get_admin(&self) -> Address {
+        self.admin.get()
+    }
+
+    pub fn get_pending_admin(&self) -> Address {
+        self.pending_admin.get()
+    }
+    
+    pub fn get_treasury(&self) -> Address {
+        self.treasury.get()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.get()
+    }
+
+    pub fn get_protocol_fee(&self) -> U256 {
+        let effective_at = self.pending_protocol_fee_effective_at.get();
+        if !effective_at.is_zero() && U256::from(self.vm().block_timestamp()) >= effective_at {
+            return self.pending_protocol_fee.get();
+        }
+        self.protocol_fee_percentage.get()
+    }
+
+    pub fn get_pending_protocol_fee(&self) -> (U256, U256) {
+        (self.pending_protocol_fee.get(), self.pending_protocol_fee_effective_at.get())
+    }
+
+    pub fn get_protocol_treasury_balance(&self) -> U256 {
+        self.protocol_treasury_balance.get(NATIVE_TOKEN)
+    }
+
+    pub fn get_protocol_token_treasury_balance(&self, token: Address) -> U256 {
+        self.protocol_treasury_balance.get(token)
+    }
+
+    pub fn get_total_value_locked(&self) -> U256 {
+        self.total_value_locked.get(NATIVE_TOKEN)
+    }
+
+    pub fn get_token_value_locked(&self, token: Address) -> U256 {
+        self.total_value_locked.get(token)
+    }
+    
+    pub fn get_user_balance(&self, user: Address) -> U256 {
+        self.user_escrow_balance.getter(user).get(NATIVE_TOKEN)
+    }
+
+    pub fn get_user_token_balance(&self, user: Address, token: Address) -> U256 {
+        self.user_escrow_balance.getter(user).get(token)
+    }
+    
+    pub fn get_provider_earnings(&self, provider: Address) -> U256 {
+        self.provider_earnings.getter(provider).get(NATIVE_TOKEN)
+    }
+
+    pub fn get_provider_token_earnings(&self, provider: Address, token: Address) -> U256 {
+        self.provider_earnings.getter(provider).get(token)
+    }
+
+    pub fn is_token_allowed(&self, token: Address) -> bool {
+        token == NATIVE_TOKEN || self.allowed_tokens.get(token)
+    }
+    
+    pub fn is_provider_registered(&self, provider: Address) -> bool {
+        self.registered_providers.get(provider)
+    }
+
+    // (name, metadata URI, payout address, registered at, verified); all zero when unregistered
+    pub fn get_provider(&self, provider: Address) -> (String, String, Address, U256, bool) {
+        (
+            self.provider_name.getter(provider).get_string(),
+            self.provider_metadata_uri.getter(provider).get_string(),
+            self.provider_payout_address.get(provider),
+            self.provider_registered_at.get(provider),
+            self.verified_providers.get(provider),
+        )
+    }
+
+    pub fn is_keeper(&self, account: Address) -> bool {
+        self.access_control.has_role(KEEPER_ROLE, account)
+    }
+
+    pub fn is_provider_verified(&self, provider: Address) -> bool {
+        self.verified_providers.get(provider)
+    }
+
+    pub fn is_permissionless_processing(&self) -> bool {
+        self.permissionless_processing.get()
+    }
+
+    pub fn get_keeper_fee(&self) -> (U256, U256, U256) {
+        (
+            self.keeper_fee_bps.get(),
+            self.min_keeper_fee.get(NATIVE_TOKEN),
+            self.max_keeper_fee.get(NATIVE_TOKEN),
+        )
+    }
+
+    // (min fee, max fee) for keepers paid in `token`
+    pub fn get_token_keeper_fee(&self, token: Address) -> (U256, U256) {
+        (self.min_keeper_fee.get(token), self.max_keeper_fee.get(token))
+    }
+
+    pub fn get_keeper_earnings(&self, keeper: Address) -> U256 {
+        self.keeper_earnings.getter(keeper).get(NATIVE_TOKEN)
+    }
+
+    pub fn get_keeper_token_earnings(&self, keeper: Address, token: Address) -> U256 {
+        self.keeper_earnings.getter(keeper).get(token)
+    }
+
+    pub fn get_user_subscriptions(&self, user: Address) -> Vec<U256> {
+        let subscriptions = self.user_subscriptions.get(user);
+        (0..subscriptions.len())
+            .map(|index| subscriptions.get(index).unwrap_or_default())
+            .collect()
+    }
+
+    pub fn get_subscription_next_due(&self, subscription_id: U256) -> U256 {
+        self.subscription_next_due.get(subscription_id)
+    }
+
+    pub fn is_subscription_active(&self, subscription_id: U256) -> bool {
+        self.subscription_status_of(subscription_id) == SubscriptionStatus::Active || self.in_final_period(subscription_id)
+    }
+
+    pub fn get_subscription_status(&self, subscription_id: U256) -> u8 {
+        self.subscription_status_of(subscription_id) as u8
+    }
+
+    pub fn get_subscription_past_due_since(&self, subscription_id: U256) -> U256 {
+        self.subscription_past_due_since.get(subscription_id)
+    }
+
+    pub fn get_plan_grace_period(&self, plan_id: U256) -> U256 {
+        self.plan_grace_period.get(plan_id)
+    }
+
+    pub fn get_plan_trial_duration(&self, plan_id: U256) -> U256 {
+        self.plan_trial_duration.get(plan_id)
+    }
+
+    pub fn has_used_trial(&self, plan_id: U256, subscriber: Address) -> bool {
+        self.plan_trial_used.getter(plan_id).get(subscriber)
+    }
+
+    pub fn get_plan_max_cycles(&self, plan_id: U256) -> U256 {
+        self.plan_max_cycles.get(plan_id)
+    }
+
+    pub fn get_subscription_payment_count(&self, subscription_id: U256) -> U256 {
+        self.subscription_payment_count.get(subscription_id)
+    }
+
+    pub fn is_allowance_funded(&self, subscription_id: U256) -> bool {
+        self.subscription_allowance_funded.get(subscription_id)
+    }
+
+    pub fn get_subscription_max_cycles(&self, subscription_id: U256) -> U256 {
+        self.subscription_max_cycles.get(subscription_id)
+    }
+
+    pub fn get_plan(&self, plan_id: U256) -> (Address, U256, U256, bool) {
+        let (price, interval) = self.plan_terms(plan_id);
+        (self.plan_provider.get(plan_id), price, interval, self.plan_active.get(plan_id))
+    }
+
+    pub fn get_plan_token(&self, plan_id: U256) -> Address {
+        self.plan_token.get(plan_id)
+    }
+
+    pub fn get_pending_plan_change(&self, plan_id: U256) -> (U256, U256, U256) {
+        (
+            self.plan_pending_price.get(plan_id),
+            self.plan_pending_interval.get(plan_id),
+            self.plan_pending_effective_at.get(plan_id),
+        )
+    }
+
+    pub fn get_plan_refund_policy(&self, plan_id: U256) -> (u8, U256) {
+        (self.plan_refund_policy.get(plan_id).to::<u8>(), self.plan_cooling_off_period.get(plan_id))
+    }
+}
+
+// Internal helpers. They live outside the #[public] impl because the router exposes
+// every fn in that block as an external method, whatever its Rust visibility.
+impl SubscriptionEscrow {
+    fn require_admin(&self, caller: Address) -> Result<(), SubscriptionError> {
+        self.access_control.check_role(ADMIN_ROLE, caller)
+    }
+
+    fn require_role_admin(&self, role: B256, caller: Address) -> Result<(), SubscriptionError> {
+        self.access_control.check_role(self.access_control.role_admin(role), caller)
+    }
+    
+    fn require_not_paused(&self) -> Result<(), SubscriptionError> {
+        if self.paused.get() {
+            return Err(SubscriptionError::ContractPaused(ContractPaused {}));
+        }
+        Ok(())
+    }
+    
+    // Renewals are safe to open up: the due-time check stops early or repeated charges
+    fn require_keeper(&self, caller: Address) -> Result<(), SubscriptionError> {
+        if self.permissionless_processing.get()
+            || self.access_control.has_role(KEEPER_ROLE, caller)
+            || self.access_control.has_role(ADMIN_ROLE, caller) {
+            return Ok(());
+        }
+        Err(SubscriptionError::MissingRole(MissingRole { account: caller, role: KEEPER_ROLE }))
+    }
+
+    // Runs one due renewal. Returns true when the renewal was charged and false when a
+    // failure was recorded: an unfunded Active subscription becomes PastDue and is retried
+    // once its grace period ends, at which point it is charged or Suspended.
+    fn process_payment(&mut self, subscription_id: U256, keeper: Address) -> Result<bool, SubscriptionError> {
+        let status = self.subscription_status_of(subscription_id);
+        if status != SubscriptionStatus::Active && status != SubscriptionStatus::PastDue {
+            return Err(SubscriptionError::SubscriptionNotActive(SubscriptionNotActive { subscriptionId: subscription_id }));
+        }
+        
+        let next_due = self.subscription_next_due.get(subscription_id);
+        let current_time = U256::from(self.vm().block_timestamp());
+        
+        if current_time < next_due {
+            return Err(SubscriptionError::PaymentNotDue(PaymentNotDue {
+                subscriptionId: subscription_id,
+                dueAt: next_due
+            }));
+        }
+        
+        let subscriber = self.subscription_subscriber.get(subscription_id);
+        let plan_id = self.subscription_plan_id.get(subscription_id);
+        let token = self.plan_token.get(plan_id);
+        let (plan_price, _) = self.sync_plan_terms(plan_id);
+        let user_balance = self.user_escrow_balance.getter(subscriber).get(token);
+        
+        // Escrow is used first; allowance-funded subscriptions pull the price when it falls short
+        let funded = user_balance >= plan_price
+            || (self.subscription_allowance_funded.get(subscription_id)
+                && self.pull_into_escrow(subscriber, token, plan_price));
+        
+        if funded {
+            self.charge_renewal(subscription_id, Some(keeper));
+            return Ok(true);
+        }
+        
+        log(self.vm(), PaymentFailed {
+            subscriptionId: subscription_id,
+            required: plan_price,
+            available: user_balance
+        });
+        
+        if status == SubscriptionStatus::Active {
+            // Re-key the queue entry to the end of the grace period
+            self.subscription_past_due_since.insert(subscription_id, current_time);
+            self.subscription_next_due.insert(subscription_id, current_time + self.plan_grace_period.get(plan_id));
+            self.queue_update(subscription_id);
+            self.set_subscription_status(subscription_id, SubscriptionStatus::PastDue);
+        } else {
+            self.queue_remove(subscription_id);
+            self.set_subscription_status(subscription_id, SubscriptionStatus::Suspended);
+        }
+        
+        Ok(false)
+    }
+
+    fn create_plan_in(&mut self, token: Address, price: U256, interval: U256) -> Result<U256, SubscriptionError> {
+        let caller = self.vm().msg_sender();
+        self.require_registered_provider(caller)?;
+        
+        if price.is_zero() || interval.is_zero() || interval > U256::from(MAX_PLAN_INTERVAL) {
+            return Err(SubscriptionError::InvalidPlanParameters(InvalidPlanParameters { price, interval }));
+        }
+        
+        let plan_id = self.next_plan_id.get();
+        
+        // Store plan data
+        self.plan_provider.insert(plan_id, caller);
+        self.plan_token.insert(plan_id, token);
+        self.plan_price.insert(plan_id, price);
+        self.plan_interval.insert(plan_id, interval);
+        self.plan_active.insert(plan_id, true);
+        
+        // Update counters
+        self.next_plan_id.set(plan_id + U256::from(1));
+        
+        log(self.vm(), PlanCreated {
+            planId: plan_id,
+            provider: caller,
+            token,
+            price,
+            interval
+        });
+        
+        Ok(plan_id)
+    }
+
+    // Shared by the subscribe entrypoints; zero `requested_cycles` takes the plan default
+    fn create_subscription(
+        &mut self,
+        caller: Address,
+        plan_id: U256,
+        requested_cycles: U256,
+        allowance_funded: bool,
+    ) -> Result<U256, SubscriptionError> {
+        self.require_not_paused()?;
+        let payment = self.vm().msg_value();
+        
+        // Validate plan exists and is active
+        let plan_provider = self.plan_provider.get(plan_id);
+        if plan_provider == Address::ZERO {
+            return Err(SubscriptionError::PlanNotFound(PlanNotFound { planId: plan_id }));
+        }
+        
+        if !self.plan_active.get(plan_id) {
+            return Err(SubscriptionError::PlanInactive(PlanInactive { planId: plan_id }));
+        }
+        
+        let plan_max_cycles = self.plan_max_cycles.get(plan_id);
+        if !plan_max_cycles.is_zero() && requested_cycles > plan_max_cycles {
+            return Err(SubscriptionError::InvalidCycleCount(InvalidCycleCount {
+                cycles: requested_cycles,
+                maxCycles: plan_max_cycles
+            }));
+        }
+        let max_cycles = if requested_cycles.is_zero() { plan_max_cycles } else { requested_cycles };
+        
+        // Delisting a token stops new subscriptions; existing ones keep renewing
+        let token = self.plan_token.get(plan_id);
+        self.require_allowed_token(token)?;
+        
+        let (plan_price, plan_interval) = self.sync_plan_terms(plan_id);
+        
+        // Handle payment deposit
+        if payment > U256::ZERO {
+            self.process_deposit(caller, NATIVE_TOKEN, payment)?;
+        }
+        
+        // The first charge, or the trial hold, is pulled from the wallet like later renewals
+        if allowance_funded {
+            if token == NATIVE_TOKEN {
+                return Err(SubscriptionError::AllowanceRequiresToken(AllowanceRequiresToken { planId: plan_id }));
+            }
+            if self.user_escrow_balance.getter(caller).get(token) < plan_price
+                && !self.pull_into_escrow(caller, token, plan_price) {
+                return Err(SubscriptionError::TokenPullFailed(TokenPullFailed { token, from: caller, amount: plan_price }));
+            }
+        }
+        
+        // Checked even when a trial applies, so trials cannot be farmed from empty wallets
+        let user_balance = self.user_escrow_balance.getter(caller).get(token);
+        if user_balance < plan_price {
+            return Err(SubscriptionError::InsufficientEscrow(InsufficientEscrow {
+                required: plan_price,
+                available: user_balance
+            }));
+        }
+        
+        let subscription_id = self.next_subscription_id.get();
+        let current_time = U256::from(self.vm().block_timestamp());
+        
+        // One trial per subscriber per plan
+        let trial_duration = self.plan_trial_duration.get(plan_id);
+        let in_trial = !trial_duration.is_zero() && !self.plan_trial_used.getter(plan_id).get(caller);
+        
+        self.subscription_plan_id.insert(subscription_id, plan_id);
+        self.subscription_subscriber.insert(subscription_id, caller);
+        self.subscription_created_at.insert(subscription_id, current_time);
+        self.set_subscription_status(subscription_id, SubscriptionStatus::Active);
+        self.subscription_max_cycles.insert(subscription_id, max_cycles);
+        self.subscription_allowance_funded.insert(subscription_id, allowance_funded);
+        self.user_subscriptions.setter(caller).push(subscription_id);
+        self.next_subscription_id.set(subscription_id + U256::from(1));
+        
+        log(self.vm(), SubscriptionCreated {
+            subscriptionId: subscription_id,
+            user: caller,
+            planId: plan_id
+        });
+        
+        if in_trial {
+            // Nothing is charged until the first renewal at the end of the trial
+            let trial_ends_at = current_time + trial_duration;
+            self.plan_trial_used.setter(plan_id).insert(caller, true);
+            self.subscription_next_due.insert(subscription_id, trial_ends_at);
+            self.queue_insert(subscription_id);
+            
+            log(self.vm(), TrialStarted {
+                subscriptionId: subscription_id,
+                user: caller,
+                trialEndsAt: trial_ends_at
+            });
+            
+            return Ok(subscription_id);
+        }
+        
+        self.subscription_last_payment.insert(subscription_id, current_time);
+        self.subscription_next_due.insert(subscription_id, current_time + plan_interval);
+        self.subscription_payment_count.insert(subscription_id, U256::from(1));
+        self.queue_insert(subscription_id);
+        
+        // The subscriber pays the first charge directly, so no keeper reward applies
+        self.user_escrow_balance.setter(caller).insert(token, user_balance - plan_price);
+        let split = self.distribute_payment(plan_provider, None, token, plan_price);
+        self.subscription_last_provider_amount.insert(subscription_id, split.provider_amount);
+        
+        log(self.vm(), PaymentProcessed {
+            from: caller,
+            to: plan_provider,
+            token,
+            amount: split.provider_amount
+        });
+        
+        // A single-cycle package is fully paid by its first charge
+        if self.cycles_exhausted(subscription_id) {
+            self.expire_subscription(subscription_id);
+        }
+        
+        Ok(subscription_id)
+    }
+
+    fn cancel_subscription_as(&mut self, caller: Address, subscription_id: U256) -> Result<U256, SubscriptionError> {
+        // Past-due and suspended subscriptions can still be cancelled outright, and an
+        // expired one until its final paid period ends
+        match self.subscription_status_of(subscription_id) {
+            SubscriptionStatus::Active | SubscriptionStatus::PastDue | SubscriptionStatus::Suspended => {},
+            SubscriptionStatus::Expired if self.in_final_period(subscription_id) => {},
+            _ => return Err(SubscriptionError::SubscriptionNotActive(SubscriptionNotActive { subscriptionId: subscription_id })),
+        }
+
+        let subscriber = self.subscription_subscriber.get(subscription_id);
+        let plan_id = self.subscription_plan_id.get(subscription_id);
+        let plan_provider = self.plan_provider.get(plan_id);
+
+        if caller != subscriber && caller != plan_provider {
+            return Err(SubscriptionError::Unauthorized(Unauthorized { caller }));
+        }
+
+        // Refunds can only come out of earnings the provider has not withdrawn yet
+        let token = self.plan_token.get(plan_id);
+        let provider_earnings = self.provider_earnings.getter(plan_provider).get(token);
+        let refund = self.calculate_refund(subscription_id).min(provider_earnings);
+
+        self.set_subscription_status(subscription_id, SubscriptionStatus::Cancelled);
+        self.queue_remove(subscription_id);
+
+        if !refund.is_zero() {
+            self.provider_earnings.setter(plan_provider).insert(token, provider_earnings - refund);
+            let user_balance = self.user_escrow_balance.getter(subscriber).get(token);
+            self.user_escrow_balance.setter(subscriber).insert(token, user_balance + refund);
+        }
+
+        log(self.vm(), SubscriptionCancelled {
+            subscriptionId: subscription_id,
+            cancelledBy: caller,
+            refundAmount: refund
+        });
+
+        Ok(refund)
+    }
+
+    fn withdraw_escrow_as(&mut self, user: Address, token: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        if amount.is_zero() {
+            return Err(SubscriptionError::ZeroAmount(ZeroAmount {}));
+        }
+
+        let balance = self.user_escrow_balance.getter(user).get(token);
+        if balance < amount {
+            return Err(SubscriptionError::InsufficientEscrow(InsufficientEscrow { required: amount, available: balance }));
+        }
+
+        self.user_escrow_balance.setter(user).insert(token, balance - amount);
+        let total_locked = self.total_value_locked.get(token);
+        self.total_value_locked.insert(token, total_locked - amount);
+
+        if self.send_asset(token, user, amount) {
+            log(self.vm(), EscrowWithdrawn { user, token, amount });
+            Ok(true)
+        } else {
+            self.user_escrow_balance.setter(user).insert(token, balance);
+            self.total_value_locked.insert(token, total_locked);
+            Err(SubscriptionError::TransferFailed(TransferFailed { to: user, amount }))
+        }
+    }
+
+    fn withdraw_provider_earnings_as(&mut self, provider: Address, token: Address) -> Result<bool, SubscriptionError> {
+        self.require_not_paused()?;
+        self.require_registered_provider(provider)?;
+        
+        let earnings = self.provider_earnings.getter(provider).get(token);
+        if earnings.is_zero() {
+            return Err(SubscriptionError::NothingToWithdraw(NothingToWithdraw { account: provider }));
+        }
+        
+        self.provider_earnings.setter(provider).insert(token, U256::ZERO);
+        let total_locked = self.total_value_locked.get(token);
+        self.total_value_locked.insert(token, total_locked - earnings);
+        
+        let payout_address = self.provider_payout_address.get(provider);
+        if self.send_asset(token, payout_address, earnings) {
+            log(self.vm(), EarningsWithdrawn { provider, token, amount: earnings });
+            Ok(true)
+        } else {
+            self.provider_earnings.setter(provider).insert(token, earnings);
+            self.total_value_locked.insert(token, total_locked);
+            Err(SubscriptionError::TransferFailed(TransferFailed { to: payout_address, amount: earnings }))
+        }
+    }
+
+    fn withdraw_protocol_fees_to(&mut self, token: Address, to: Address, amount: U256) -> Result<bool, SubscriptionError> {
+        self.require_not_paused()?;
+        if amount.is_zero() {
+            return Err(SubscriptionError::ZeroAmount(ZeroAmount {}));
+        }
+
+        // Fees go to the configured treasury unless an explicit recipient is given
+        let recipient = if to == Address::ZERO { self.treasury.get() } else { to };
+        let treasury_balance = self.protocol_treasury_balance.get(token);
+        if treasury_balance < amount {
+            return Err(SubscriptionError::InsufficientTreasury(InsufficientTreasury {
+                required: amount,
+                available: treasury_balance
+            }));
+        }
+
+        self.protocol_treasury_balance.insert(token, treasury_balance - amount);
+        let total_locked = self.total_value_locked.get(token);
+        self.total_value_locked.insert(token, total_locked - amount);
+
+        if self.send_asset(token, recipient, amount) {
+            log(self.vm(), ProtocolFeesWithdrawn { to: recipient, token, amount });
+            Ok(true)
+        } else {
+            self.protocol_treasury_balance.insert(token, treasury_balance);
+            self.total_value_locked.insert(token, total_locked);
+            Err(SubscriptionError::TransferFailed(TransferFailed { to: recipient, amount }))
+        }
+    }
+
+    fn withdraw_keeper_earnings_as(&mut self, keeper: Address, token: Address) -> Result<bool, SubscriptionError> {
+        self.require_not_paused()?;
+        let earnings = self.keeper_earnings.getter(keeper).get(token);
+        if earnings.is_zero() {
+            return Err(SubscriptionError::NothingToWithdraw(NothingToWithdraw { account: keeper }));
+        }
+
+        self.keeper_earnings.setter(keeper).insert(token, U256::ZERO);
+        let total_locked = self.total_value_locked.get(token);
+        self.total_value_locked.insert(token, total_locked - earnings);
+
+        if self.send_asset(token, keeper, earnings) {
+            log(self.vm(), KeeperEarningsWithdrawn { keeper, token, amount: earnings });
+            Ok(true)
+        } else {
+            self.keeper_earnings.setter(keeper).insert(token, earnings);
+            self.total_value_locked.insert(token, total_locked);
+            Err(SubscriptionError::TransferFailed(TransferFailed { to: keeper, amount: earnings }))
+        }
+    }
+
+    // Checks an intent signed by `signer` and consumes its nonce
+    fn verify_intent(
+        &mut self,
+        signer: Address,
+        struct_hash: B256,
+        deadline: U256,
+        v: u8,
+        r: B256,
+        s: B256,
+    ) -> Result<(), SubscriptionError> {
+        if U256::from(self.vm().block_timestamp()) > deadline {
+            return Err(SubscriptionError::SignatureExpired(SignatureExpired { deadline }));
+        }
+
+        let digest = utils::typed_data_digest(self.get_domain_separator(), struct_hash);
+        if utils::recover_signer(self.vm(), digest, v, r, s) != Some(signer) {
+            return Err(SubscriptionError::InvalidSignature(InvalidSignature { signer }));
+        }
+
+        let nonce = self.intent_nonces.get(signer);
+        self.intent_nonces.insert(signer, nonce + U256::from(1));
+        log(self.vm(), IntentExecuted { signer, relayer: self.vm().msg_sender(), nonce });
+
+        Ok(())
+    }
+
+    fn require_registered_provider(&self, provider: Address) -> Result<(), SubscriptionError> {
+        if !self.registered_providers.get(provider) {
+            return Err(SubscriptionError::ProviderNotRegistered(ProviderNotRegistered { provider }));
+        }
+        Ok(())
+    }
+
+    fn require_plan_provider(&self, plan_id: U256, caller: Address) -> Result<(), SubscriptionError> {
+        let plan_provider = self.plan_provider.get(plan_id);
+        if plan_provider == Address::ZERO {
+            return Err(SubscriptionError::PlanNotFound(PlanNotFound { planId: plan_id }));
+        }
+        if plan_provider != caller {
+            return Err(SubscriptionError::Unauthorized(Unauthorized { caller }));
+        }
+        Ok(())
+    }
+
+    fn calculate_refund(&self, subscription_id: U256) -> U256 {
+        let plan_id = self.subscription_plan_id.get(subscription_id);
+        let paid = self.subscription_last_provider_amount.get(subscription_id);
+        let current_time = U256::from(self.vm().block_timestamp());
+
+        match RefundPolicy::from_u8(self.plan_refund_policy.get(plan_id).to::<u8>()) {
+            Some(RefundPolicy::Prorated) => {
+                // Measured against the period that was charged, which a later plan update
+                // does not change; past-due subscriptions have no paid time left
+                if self.subscription_status_of(subscription_id) != SubscriptionStatus::Active
+                    && !self.in_final_period(subscription_id) {
+                    return U256::ZERO;
+                }
+                let last_payment = self.subscription_last_payment.get(subscription_id);
+                let period_end = self.subscription_next_due.get(subscription_id);
+                if current_time >= period_end {
+                    return U256::ZERO;
+                }
+                paid * (period_end - current_time) / (period_end - last_payment)
+            },
+            Some(RefundPolicy::CoolingOff) => {
+                let created_at = self.subscription_created_at.get(subscription_id);
+                if current_time < created_at + self.plan_cooling_off_period.get(plan_id) {
+                    paid
+                } else {
+                    U256::ZERO
+                }
+            },
+            _ => U256::ZERO,
+        }
+    }
+    
+    // Activates a staged protocol fee once its delay has passed and returns the fee in force
+    fn sync_protocol_fee(&mut self) -> U256 {
+        let current_fee = self.protocol_fee_percentage.get();
+        let effective_at = self.pending_protocol_fee_effective_at.get();
+        if effective_at.is_zero() || U256::from(self.vm().block_timestamp()) < effective_at {
+            return current_fee;
+        }
+
+        let new_fee = self.pending_protocol_fee.get();
+        self.protocol_fee_percentage.set(new_fee);
+        self.pending_protocol_fee.set(U256::ZERO);
+        self.pending_protocol_fee_effective_at.set(U256::ZERO);
+
+        log(self.vm(), ProtocolFeeChanged {
+            previousFee: current_fee,
+            newFee: new_fee
+        });
+
+        new_fee
+    }
+    
+    // Price and interval in force for a plan, counting a staged change that is now due
+    fn plan_terms(&self, plan_id: U256) -> (U256, U256) {
+        let effective_at = self.plan_pending_effective_at.get(plan_id);
+        if !effective_at.is_zero() && U256::from(self.vm().block_timestamp()) >= effective_at {
+            return (self.plan_pending_price.get(plan_id), self.plan_pending_interval.get(plan_id));
+        }
+        (self.plan_price.get(plan_id), self.plan_interval.get(plan_id))
+    }
+
+    // Activates a staged plan change once its notice period has passed
+    fn sync_plan_terms(&mut self, plan_id: U256) -> (U256, U256) {
+        let effective_at = self.plan_pending_effective_at.get(plan_id);
+        if effective_at.is_zero() || U256::from(self.vm().block_timestamp()) < effective_at {
+            return (self.plan_price.get(plan_id), self.plan_interval.get(plan_id));
+        }
+
+        let price = self.plan_pending_price.get(plan_id);
+        let interval = self.plan_pending_interval.get(plan_id);
+        self.plan_price.insert(plan_id, price);
+        self.plan_interval.insert(plan_id, interval);
+        self.plan_pending_price.insert(plan_id, U256::ZERO);
+        self.plan_pending_interval.insert(plan_id, U256::ZERO);
+        self.plan_pending_effective_at.insert(plan_id, U256::ZERO);
+
+        log(self.vm(), PlanUpdated { planId: plan_id, price, interval });
+
+        (price, interval)
     }
     
-    fn process_deposit(&mut self, user: Address, amount: U256) -> Result<(), SubscriptionError> {
+    fn process_deposit(&mut self, user: Address, token: Address, amount: U256) -> Result<(), SubscriptionError> {
         if amount.is_zero() {
-            return Err(SubscriptionError::InvalidInput(InvalidInput {}));
+            return Err(SubscriptionError::ZeroAmount(ZeroAmount {}));
         }
         
         // Update user balances
-        let current_balance = self.user_escrow_balance.get(user);
-        self.user_escrow_balance.insert(user, current_balance + amount);
+        let current_balance = self.user_escrow_balance.getter(user).get(token);
+        self.user_escrow_balance.setter(user).insert(token, current_balance + amount);
         
         // Update contract TVL
-        let total_locked = self.total_value_locked.get();
-        self.total_value_locked.set(total_locked + amount);
+        let total_locked = self.total_value_locked.get(token);
+        self.total_value_locked.insert(token, total_locked + amount);
         
         Ok(())
     }
+
+    fn require_allowed_token(&self, token: Address) -> Result<(), SubscriptionError> {
+        if !self.is_token_allowed(token) {
+            return Err(SubscriptionError::TokenNotAllowed(TokenNotAllowed { token }));
+        }
+        Ok(())
+    }
+
+    // Pulls `amount` of an allowance-funded payment into the user's escrow balance
+    fn pull_into_escrow(&mut self, user: Address, token: Address, amount: U256) -> bool {
+        let contract = self.vm().contract_address();
+        if !self.call_token(token, IERC20::transferFromCall { from: user, to: contract, amount }.abi_encode()) {
+            return false;
+        }
+        self.process_deposit(user, token, amount).is_ok()
+    }
+
+    // Pays out ETH or an ERC-20 balance held by the escrow
+    fn send_asset(&mut self, token: Address, to: Address, amount: U256) -> bool {
+        if token == NATIVE_TOKEN {
+            return self.vm().transfer_eth(to, amount).is_ok();
+        }
+        self.call_token(token, IERC20::transferCall { to, amount }.abi_encode())
+    }
+
+    // Unreadable allowances count as zero
+    fn token_allowance(&self, token: Address, owner: Address, spender: Address) -> U256 {
+        let calldata = IERC20::allowanceCall { owner, spender }.abi_encode();
+        self.vm()
+            .static_call(&Call::new(), token, &calldata)
+            .ok()
+            .and_then(|output| IERC20::allowanceCall::abi_decode_returns(&output, true).ok())
+            .map_or(U256::ZERO, |ret| ret._0)
+    }
+
+    // Tokens that return nothing (such as USDT) are treated as successful unless they revert.
+    // A call to an address without code also returns nothing, so that case needs deployed code.
+    fn call_token(&mut self, token: Address, calldata: Vec<u8>) -> bool {
+        match self.vm().call(&Call::new(), token, &calldata) {
+            Ok(output) if output.is_empty() => self.vm().code_size(token) > 0,
+            Ok(output) => IERC20::transferCall::abi_decode_returns(&output, true).is_ok_and(|ret| ret._0),
+            Err(_) => false,
+        }
+    }
+}
+
+// Provider profile helpers
+impl SubscriptionEscrow {
+    fn validate_profile(name: &str, metadata_uri: &str) -> Result<(), SubscriptionError> {
+        if name.len() > MAX_PROVIDER_NAME_LENGTH {
+            return Err(SubscriptionError::NameTooLong(NameTooLong {
+                length: U256::from(name.len()),
+                maxLength: U256::from(MAX_PROVIDER_NAME_LENGTH)
+            }));
+        }
+        if metadata_uri.len() > MAX_METADATA_URI_LENGTH {
+            return Err(SubscriptionError::MetadataUriTooLong(MetadataUriTooLong {
+                length: U256::from(metadata_uri.len()),
+                maxLength: U256::from(MAX_METADATA_URI_LENGTH)
+            }));
+        }
+        Ok(())
+    }
+}
+
+// Reentrancy guard
+impl SubscriptionEscrow {
+    // Runs `body` with the storage lock held. Value-moving entrypoints go through here so a
+    // receiver or token contract cannot call back in while balances are mid-update.
+    fn non_reentrant<T>(
+        &mut self,
+        body: impl FnOnce(&mut Self) -> Result<T, SubscriptionError>,
+    ) -> Result<T, SubscriptionError> {
+        if self.reentrancy_locked.get() {
+            return Err(SubscriptionError::ReentrantCall(ReentrantCall {}));
+        }
+
+        self.reentrancy_locked.set(true);
+        let result = body(self);
+        self.reentrancy_locked.set(false);
+        result
+    }
+}
+
+// Subscription lifecycle helpers that use non-ABI types
+impl SubscriptionEscrow {
+    // Debits the subscriber and starts a new billing period; callers check the balance first
+    fn charge_renewal(&mut self, subscription_id: U256, keeper: Option<Address>) {
+        let subscriber = self.subscription_subscriber.get(subscription_id);
+        let plan_id = self.subscription_plan_id.get(subscription_id);
+        let plan_provider = self.plan_provider.get(plan_id);
+        let plan_price = self.plan_price.get(plan_id);
+        let current_time = U256::from(self.vm().block_timestamp());
+        
+        let token = self.plan_token.get(plan_id);
+        
+        let user_balance = self.user_escrow_balance.getter(subscriber).get(token);
+        self.user_escrow_balance.setter(subscriber).insert(token, user_balance - plan_price);
+        let split = self.distribute_payment(plan_provider, keeper, token, plan_price);
+        self.subscription_last_payment.insert(subscription_id, current_time);
+        self.subscription_last_provider_amount.insert(subscription_id, split.provider_amount);
+        self.subscription_next_due.insert(subscription_id, current_time + self.plan_interval.get(plan_id));
+        let payment_count = self.subscription_payment_count.get(subscription_id);
+        self.subscription_payment_count.insert(subscription_id, payment_count + U256::from(1));
+        self.queue_update(subscription_id);
+        
+        if self.subscription_status_of(subscription_id) == SubscriptionStatus::PastDue {
+            self.subscription_past_due_since.insert(subscription_id, U256::ZERO);
+            self.set_subscription_status(subscription_id, SubscriptionStatus::Active);
+        }
+        
+        log(self.vm(), PaymentProcessed { from: subscriber, to: plan_provider, token, amount: split.provider_amount });
+        
+        if self.cycles_exhausted(subscription_id) {
+            self.expire_subscription(subscription_id);
+        }
+    }
+
+    // Settles the user's past-due subscriptions, oldest first, while the balance covers them
+    fn recover_past_due(&mut self, user: Address) {
+        // Catch-up charges are renewals and wait for unpause like any other
+        if self.paused.get() {
+            return;
+        }
+        
+        let subscriptions = self.user_subscriptions.get(user).len();
+        for index in 0..subscriptions {
+            let subscription_id = self.user_subscriptions.get(user).get(index).unwrap_or_default();
+            if self.subscription_status_of(subscription_id) != SubscriptionStatus::PastDue {
+                continue;
+            }
+            
+            let plan_id = self.subscription_plan_id.get(subscription_id);
+            let (plan_price, _) = self.sync_plan_terms(plan_id);
+            if self.user_escrow_balance.getter(user).get(self.plan_token.get(plan_id)) >= plan_price {
+                self.charge_renewal(subscription_id, None);
+            }
+        }
+    }
+
+    // Charging the last paid cycle ends renewals: the subscription leaves the due queue
+    // and stays usable until the period that charge paid for runs out
+    fn expire_subscription(&mut self, subscription_id: U256) {
+        self.queue_remove(subscription_id);
+        self.set_subscription_status(subscription_id, SubscriptionStatus::Expired);
+        
+        log(self.vm(), SubscriptionExpired {
+            subscriptionId: subscription_id,
+            paymentCount: self.subscription_payment_count.get(subscription_id),
+            endsAt: self.subscription_next_due.get(subscription_id)
+        });
+    }
+
+    fn cycles_exhausted(&self, subscription_id: U256) -> bool {
+        let max_cycles = self.subscription_max_cycles.get(subscription_id);
+        !max_cycles.is_zero() && self.subscription_payment_count.get(subscription_id) >= max_cycles
+    }
+
+    fn set_subscription_status(&mut self, subscription_id: U256, status: SubscriptionStatus) {
+        let previous_status = self.subscription_status.get(subscription_id).to::<u8>();
+        self.subscription_status.insert(subscription_id, U8::from(status as u8));
+        
+        log(self.vm(), SubscriptionStatusChanged {
+            subscriptionId: subscription_id,
+            previousStatus: previous_status,
+            newStatus: status as u8
+        });
+    }
+
+    fn is_renewal_tracked(&self, subscription_id: U256) -> bool {
+        matches!(
+            self.subscription_status_of(subscription_id),
+            SubscriptionStatus::Active | SubscriptionStatus::PastDue
+        )
+    }
+
+    fn subscription_status_of(&self, subscription_id: U256) -> SubscriptionStatus {
+        SubscriptionStatus::from_u8(self.subscription_status.get(subscription_id).to::<u8>())
+            .unwrap_or(SubscriptionStatus::None)
+    }
+
+    // An expired subscription keeps the period its last charge paid for
+    fn in_final_period(&self, subscription_id: U256) -> bool {
+        self.subscription_status_of(subscription_id) == SubscriptionStatus::Expired
+            && U256::from(self.vm().block_timestamp()) < self.subscription_next_due.get(subscription_id)
+    }
+}
+
+// Payment accounting helpers that use non-ABI types
+impl SubscriptionEscrow {
+    // Single source of truth for fee splits, shared by subscribe and renewals
+    fn split_payment(&mut self, amount: U256, token: Address, with_keeper: bool) -> PaymentSplit {
+        let protocol_fee = (amount * self.sync_protocol_fee()) / U256::from(10000);
+
+        let mut keeper_fee = U256::ZERO;
+        if with_keeper {
+            keeper_fee = (amount * self.keeper_fee_bps.get()) / U256::from(10000);
+            keeper_fee = keeper_fee
+                .max(self.min_keeper_fee.get(token))
+                .min(self.max_keeper_fee.get(token))
+                .min(amount - protocol_fee);
+        }
+
+        PaymentSplit {
+            provider_amount: amount - protocol_fee - keeper_fee,
+            protocol_fee,
+            keeper_fee,
+        }
+    }
+
+    // Credits an already-debited charge to the provider, treasury and keeper balances
+    fn distribute_payment(
+        &mut self,
+        provider: Address,
+        keeper: Option<Address>,
+        token: Address,
+        amount: U256,
+    ) -> PaymentSplit {
+        let split = self.split_payment(amount, token, keeper.is_some());
+
+        let provider_earnings = self.provider_earnings.getter(provider).get(token);
+        self.provider_earnings.setter(provider).insert(token, provider_earnings + split.provider_amount);
+
+        let treasury_balance = self.protocol_treasury_balance.get(token);
+        self.protocol_treasury_balance.insert(token, treasury_balance + split.protocol_fee);
+
+        if let Some(keeper) = keeper {
+            if !split.keeper_fee.is_zero() {
+                let keeper_earnings = self.keeper_earnings.getter(keeper).get(token);
+                self.keeper_earnings.setter(keeper).insert(token, keeper_earnings + split.keeper_fee);
+            }
+        }
+
+        split
+    }
+}
+
+// Due-payment queue, kept as a binary min-heap in storage. Ties on the due time are
+// broken by subscription id so the order is deterministic.
+impl SubscriptionEscrow {
+    fn due_key(&self, subscription_id: U256) -> (U256, U256) {
+        (self.subscription_next_due.get(subscription_id), subscription_id)
+    }
+
+    fn heap_entry(&self, index: usize) -> U256 {
+        self.due_heap.get(index).unwrap_or_default()
+    }
+
+    fn heap_place(&mut self, index: usize, subscription_id: U256) {
+        if let Some(mut slot) = self.due_heap.setter(index) {
+            slot.set(subscription_id);
+        }
+        self.due_heap_position.insert(subscription_id, U256::from(index + 1));
+    }
+
+    fn queue_insert(&mut self, subscription_id: U256) {
+        let index = self.due_heap.len();
+        self.due_heap.push(subscription_id);
+        self.due_heap_position.insert(subscription_id, U256::from(index + 1));
+        self.sift_up(index);
+        self.bucket_insert(subscription_id);
+    }
+
+    fn queue_remove(&mut self, subscription_id: U256) {
+        let position = self.due_heap_position.get(subscription_id);
+        if position.is_zero() {
+            return;
+        }
+
+        self.bucket_remove(subscription_id);
+
+        let index = position.to::<usize>() - 1;
+        let last_index = self.due_heap.len() - 1;
+        let last = self.due_heap.pop().unwrap_or_default();
+        self.due_heap_position.insert(subscription_id, U256::ZERO);
+
+        if index != last_index {
+            self.heap_place(index, last);
+            self.sift_up(index);
+            self.sift_down(self.due_heap_position.get(last).to::<usize>() - 1);
+        }
+    }
+
+    // Restores heap order after a subscription's next due time changed
+    fn queue_update(&mut self, subscription_id: U256) {
+        let position = self.due_heap_position.get(subscription_id);
+        if position.is_zero() {
+            return;
+        }
+
+        let index = position.to::<usize>() - 1;
+        self.sift_up(index);
+        self.sift_down(self.due_heap_position.get(subscription_id).to::<usize>() - 1);
+
+        if Self::due_bucket(self.subscription_next_due.get(subscription_id)) != self.due_bucket_of.get(subscription_id) {
+            self.bucket_remove(subscription_id);
+            self.bucket_insert(subscription_id);
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        let subscription_id = self.heap_entry(index);
+        let key = self.due_key(subscription_id);
+
+        while index > 0 {
+            let parent_index = (index - 1) / 2;
+            let parent = self.heap_entry(parent_index);
+            if self.due_key(parent) <= key {
+                break;
+            }
+            self.heap_place(index, parent);
+            index = parent_index;
+        }
+
+        self.heap_place(index, subscription_id);
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        let len = self.due_heap.len();
+        let subscription_id = self.heap_entry(index);
+        let key = self.due_key(subscription_id);
+
+        loop {
+            let left = 2 * index + 1;
+            if left >= len {
+                break;
+            }
+
+            let mut child_index = left;
+            let mut child_key = self.due_key(self.heap_entry(left));
+            if left + 1 < len {
+                let right_key = self.due_key(self.heap_entry(left + 1));
+                if right_key < child_key {
+                    child_index = left + 1;
+                    child_key = right_key;
+                }
+            }
+
+            if key <= child_key {
+                break;
+            }
+            let child = self.heap_entry(child_index);
+            self.heap_place(index, child);
+            index = child_index;
+        }
+
+        self.heap_place(index, subscription_id);
+    }
+
+    // Walks the heap in due order without mutating it. Only children of visited nodes are
+    // read, so the cost is bounded by `limit` rather than heap size.
+    fn collect_due(&self, limit: usize) -> Vec<U256> {
+        let current_time = U256::from(self.vm().block_timestamp());
+        let mut due = Vec::new();
+        let mut frontier: Vec<((U256, U256), usize)> = Vec::new();
+
+        if !self.due_heap.is_empty() {
+            frontier.push((self.due_key(self.heap_entry(0)), 0));
+        }
+
+        while !frontier.is_empty() && due.len() < limit {
+            let mut next = 0;
+            for (candidate, entry) in frontier.iter().enumerate() {
+                if entry.0 < frontier[next].0 {
+                    next = candidate;
+                }
+            }
+            let (_, index) = frontier.swap_remove(next);
+
+            let subscription_id = self.heap_entry(index);
+            if self.subscription_next_due.get(subscription_id) > current_time {
+                break;
+            }
+
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.due_heap.len() {
+                    frontier.push((self.due_key(self.heap_entry(child)), child));
+                }
+            }
+            due.push(subscription_id);
+        }
+
+        due
+    }
+}
+
+// Due-time buckets. Unlike the heap they can be resumed at any position, which lets
+// checker_all page through any number of due entries. Slots are never compacted, so a
+// cursor stays valid while the ids before it are charged and rescheduled.
+impl SubscriptionEscrow {
+    fn due_bucket(due_time: U256) -> U256 {
+        due_time / U256::from(DUE_BUCKET_SPAN)
+    }
+
+    fn bucket_cursor(bucket: U256, offset: usize) -> U256 {
+        (bucket << 128) | U256::from(offset)
+    }
+
+    fn bucket_insert(&mut self, subscription_id: U256) {
+        let bucket = Self::due_bucket(self.subscription_next_due.get(subscription_id));
+        let mut entries = self.due_buckets.setter(bucket);
+        entries.push(subscription_id);
+        let slot = U256::from(entries.len());
+
+        self.due_bucket_of.insert(subscription_id, bucket);
+        self.due_bucket_slot.insert(subscription_id, slot);
+    }
+
+    // Clears the slot in place; collect_due_page skips slots that are no longer live
+    fn bucket_remove(&mut self, subscription_id: U256) {
+        let slot = self.due_bucket_slot.get(subscription_id);
+        if slot.is_zero() {
+            return;
+        }
+
+        let bucket = self.due_bucket_of.get(subscription_id);
+        if let Some(mut entry) = self.due_buckets.setter(bucket).setter(slot.to::<usize>() - 1) {
+            entry.set(U256::ZERO);
+        }
+        self.due_bucket_slot.insert(subscription_id, U256::ZERO);
+    }
+
+    // A slot is stale once its id was removed or re-bucketed, even if the id was re-added
+    fn is_live_slot(&self, bucket: U256, offset: usize, subscription_id: U256) -> bool {
+        self.due_bucket_of.get(subscription_id) == bucket
+            && self.due_bucket_slot.get(subscription_id) == U256::from(offset + 1)
+    }
+
+    // Walks the buckets from `cursor` up to the current hour, starting at the earliest
+    // due bucket when `cursor` is zero. Every step examines one entry or moves past one
+    // bucket, so each page makes progress and visits at most MAX_DUE_SCAN positions.
+    // Returns the due ids and a continuation cursor pointing at the next due entry, which
+    // is zero once the current hour's bucket has been examined.
+    fn collect_due_page(&self, cursor: U256, limit: usize) -> (Vec<U256>, U256) {
+        let current_time = U256::from(self.vm().block_timestamp());
+        let last_bucket = Self::due_bucket(current_time);
+        let mut due = Vec::new();
+
+        // The heap top is the earliest due entry, so nothing is due when it is not
+        let (mut bucket, mut offset) = if cursor.is_zero() {
+            let earliest = self.heap_entry(0);
+            if self.due_heap.is_empty() || self.subscription_next_due.get(earliest) > current_time {
+                return (due, U256::ZERO);
+            }
+            (self.due_bucket_of.get(earliest), 0)
+        } else {
+            (cursor >> 128, (cursor & U256::from(u128::MAX)).saturating_to::<usize>())
+        };
+
+        for _ in 0..MAX_DUE_SCAN {
+            if bucket > last_bucket {
+                return (due, U256::ZERO);
+            }
+
+            let Some(subscription_id) = self.due_buckets.get(bucket).get(offset) else {
+                bucket += U256::from(1);
+                offset = 0;
+                continue;
+            };
+            if self.is_live_slot(bucket, offset, subscription_id)
+                && self.subscription_next_due.get(subscription_id) <= current_time {
+                if due.len() == limit {
+                    return (due, Self::bucket_cursor(bucket, offset));
+                }
+                due.push(subscription_id);
+            }
+            offset += 1;
+        }
+
+        if bucket > last_bucket {
+            return (due, U256::ZERO);
+        }
+        (due, Self::bucket_cursor(bucket, offset))
+    }
+}
+
+// Gelato exec payloads
+impl SubscriptionEscrow {
+    fn single_payment_payload(subscription_id: U256) -> Vec<u8> {
+        ISubscriptionAutomation::processSubscriptionPaymentCall {
+            subscriptionId: subscription_id,
+        }
+        .abi_encode()
+    }
+
+    fn batch_payment_payload(ids: &[U256]) -> Vec<u8> {
+        ISubscriptionAutomation::processPaymentsBatchCall { ids: ids.to_vec() }.abi_encode()
+    }
 }
@@ -0,0 +1,63 @@
+//! EIP-712 typed-data helpers used to verify signed intents.
+
+use alloc::vec::Vec;
+
+use stylus_sdk::{
+    alloy_primitives::{Address, B256, U256},
+    alloy_sol_types::SolValue,
+    crypto::keccak,
+    keccak_const::Keccak256,
+    prelude::*,
+    stylus_core::calls::context::Call,
+};
+
+pub const EIP712_DOMAIN_TYPEHASH: [u8; 32] = Keccak256::new()
+    .update(b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)")
+    .finalize();
+
+// ecrecover precompile
+const ECRECOVER: Address = Address::with_last_byte(1);
+
+// Upper bound on `s` for canonical signatures (secp256k1 order / 2), per EIP-2
+const SECP256K1_HALF_ORDER: U256 = U256::from_be_bytes([
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+]);
+
+pub fn domain_separator(name: &str, version: &str, chain_id: u64, verifying_contract: Address) -> B256 {
+    keccak(
+        (
+            B256::from(EIP712_DOMAIN_TYPEHASH),
+            keccak(name.as_bytes()),
+            keccak(version.as_bytes()),
+            U256::from(chain_id),
+            verifying_contract,
+        )
+            .abi_encode(),
+    )
+}
+
+// keccak256("\x19\x01" ‖ domainSeparator ‖ hashStruct(message))
+pub fn typed_data_digest(domain_separator: B256, struct_hash: B256) -> B256 {
+    let mut preimage = Vec::with_capacity(66);
+    preimage.extend_from_slice(&[0x19, 0x01]);
+    preimage.extend_from_slice(domain_separator.as_slice());
+    preimage.extend_from_slice(struct_hash.as_slice());
+    keccak(preimage)
+}
+
+// Returns None for malformed or malleable signatures and for anything ecrecover rejects
+pub fn recover_signer(vm: &dyn Host, digest: B256, v: u8, r: B256, s: B256) -> Option<Address> {
+    if (v != 27 && v != 28) || U256::from_be_bytes(s.0) > SECP256K1_HALF_ORDER {
+        return None;
+    }
+
+    let input = (digest, U256::from(v), r, s).abi_encode();
+    let output = vm.static_call(&Call::new(), ECRECOVER, &input).ok()?;
+    if output.len() != 32 {
+        return None;
+    }
+
+    let signer = Address::from_slice(&output[12..]);
+    (signer != Address::ZERO).then_some(signer)
+}
@@ -1,187 +1,1573 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use stylus_sdk::alloy_primitives::{Address, U256};
-    
-    // Mock addresses for testing
-    const USER_ADDR: Address = Address::repeat_byte(0x01);
-    const PROVIDER_ADDR: Address = Address::repeat_byte(0x02);
-    const OTHER_ADDR: Address = Address::repeat_byte(0x03);
-    
-    fn setup_contract() -> HybridEscrowRecurring {
-        let mut contract = HybridEscrowRecurring::default();
-        // Initialize would typically be called by owner
-        contract.owner.set(USER_ADDR);
-        contract.paused.set(false);
-        contract.emergency_stopped.set(false);
-        contract.sub_count.set(U256::ZERO);
-        contract.locked.set(false);
-        contract
-    }
-    
-    #[test]
-    fn test_initialization() {
-        let contract = setup_contract();
-        assert_eq!(contract.owner.get(), USER_ADDR);
-        assert_eq!(contract.paused.get(), false);
-        assert_eq!(contract.sub_count.get(), U256::ZERO);
-    }
-    
-    #[test]
-    fn test_subscription_creation_validation() {
-        let mut contract = setup_contract();
-        
-        // Test invalid provider
-        let result = contract.create_subscription(
-            Address::ZERO,
-            U256::from(100),
-            300, // 5 minutes
-            12,  // 12 intervals
-        );
-        assert!(result.is_err());
-        
-        // Test zero amount
-        let result = contract.create_subscription(
-            PROVIDER_ADDR,
-            U256::ZERO,
-            300,
-            12,
-        );
-        assert!(result.is_err());
-        
-        // Test interval too short
-        let result = contract.create_subscription(
-            PROVIDER_ADDR,
-            U256::from(100),
-            30, // Less than 60 seconds
-            12,
+use stylus_sdk::abi::Router;
+use stylus_sdk::alloy_primitives::{Address, B256, U256};
+use stylus_sdk::alloy_sol_types::{SolCall, SolEvent, SolValue};
+use stylus_sdk::crypto::keccak;
+use stylus_sdk::function_selector;
+use stylus_sdk::prelude::*;
+use stylus_sdk::testing::TestVM;
+use subscription_engine::access_control::{ADMIN_ROLE, FEE_MANAGER_ROLE, GUARDIAN_ROLE, KEEPER_ROLE, PROVIDER_VERIFIER_ROLE};
+use subscription_engine::{
+    utils, IERC20Permit, ISubscriptionAutomation, RefundPolicy, SubscriptionError, SubscriptionEscrow,
+    SubscriptionExpired, SubscriptionStatus, CANCEL_TYPEHASH, DEFAULT_PROTOCOL_FEE_BPS, EIP712_NAME, EIP712_VERSION, IERC20, MAX_BATCH_SIZE, MAX_DUE_SCAN, MAX_GRACE_PERIOD,
+    MAX_PLAN_INTERVAL, MAX_PROTOCOL_FEE_BPS, MAX_TRIAL_DURATION, MIN_FEE_CHANGE_DELAY, PLAN_CHANGE_NOTICE_PERIOD, SUBSCRIBE_TYPEHASH,
+    WITHDRAW_ESCROW_TYPEHASH,
+};
+
+// Mock addresses for testing
+const ADMIN_ADDR: Address = Address::repeat_byte(0x01);
+const PROVIDER_ADDR: Address = Address::repeat_byte(0x02);
+const USER_ADDR: Address = Address::repeat_byte(0x03);
+const CONTRACT_ADDR: Address = Address::repeat_byte(0xc0);
+
+const PLAN_PRICE: u64 = 1_000;
+const PLAN_INTERVAL: u64 = 86_400;
+const START_TIME: u64 = 1_700_000_000;
+
+// Opaque to the contract, which forwards it to the token's permit
+const PERMIT_SIGNATURE: (u8, B256, B256) = (27, B256::repeat_byte(0x0a), B256::repeat_byte(0x0b));
+
+fn setup_contract() -> (TestVM, SubscriptionEscrow) {
+    let vm = TestVM::default();
+    vm.set_contract_address(CONTRACT_ADDR);
+    vm.set_block_timestamp(START_TIME);
+
+    let mut contract = SubscriptionEscrow::from(&vm);
+    vm.set_sender(ADMIN_ADDR);
+    contract
+        .constructor(ADMIN_ADDR, ADMIN_ADDR, U256::from(DEFAULT_PROTOCOL_FEE_BPS), Address::ZERO)
+        .unwrap();
+    (vm, contract)
+}
+
+fn setup_plan(vm: &TestVM, contract: &mut SubscriptionEscrow) -> U256 {
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    contract
+        .create_plan(U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap()
+}
+
+// Dispatches calldata through the ABI router the way an external call reaches the contract
+fn route(contract: &mut SubscriptionEscrow, selector: [u8; 4], input: &[u8]) -> Option<Result<Vec<u8>, Vec<u8>>> {
+    <SubscriptionEscrow as Router<SubscriptionEscrow>>::route(contract, u32::from_be_bytes(selector), input)
+}
+
+// Stand-in for an ERC-20 at a fixed address. The test VM cannot execute another
+// contract, so each expected token call is programmed with its response.
+struct MockErc20 {
+    address: Address,
+}
+
+impl MockErc20 {
+    fn new(vm: &TestVM, contract: &mut SubscriptionEscrow) -> Self {
+        let token = Self { address: Address::repeat_byte(0xe2) };
+        vm.set_code(token.address, vec![0x00]);
+        vm.set_sender(ADMIN_ADDR);
+        contract.set_token_allowed(token.address, true).unwrap();
+        token
+    }
+
+    fn respond(&self, vm: &TestVM, calldata: Vec<u8>, success: bool) {
+        let response = if success {
+            Ok(U256::from(1).to_be_bytes::<32>().to_vec())
+        } else {
+            Err(Vec::new())
+        };
+        vm.mock_call(self.address, calldata, response);
+    }
+
+    fn expect_transfer_from(&self, vm: &TestVM, from: Address, amount: u64, success: bool) {
+        let calldata = IERC20::transferFromCall { from, to: CONTRACT_ADDR, amount: U256::from(amount) }.abi_encode();
+        self.respond(vm, calldata, success);
+    }
+
+    fn expect_permit(&self, vm: &TestVM, owner: Address, value: u64, deadline: u64, success: bool) {
+        let (v, r, s) = PERMIT_SIGNATURE;
+        let calldata = IERC20Permit::permitCall {
+            owner,
+            spender: CONTRACT_ADDR,
+            value: U256::from(value),
+            deadline: U256::from(deadline),
+            v,
+            r,
+            s,
+        }
+        .abi_encode();
+        // permit returns nothing on success
+        vm.mock_call(self.address, calldata, if success { Ok(Vec::new()) } else { Err(Vec::new()) });
+    }
+
+    fn set_allowance(&self, vm: &TestVM, owner: Address, amount: u64) {
+        let calldata = IERC20::allowanceCall { owner, spender: CONTRACT_ADDR }.abi_encode();
+        vm.mock_static_call(self.address, calldata, Ok(U256::from(amount).abi_encode()));
+    }
+
+    fn expect_transfer(&self, vm: &TestVM, to: Address, amount: u64, success: bool) {
+        let calldata = IERC20::transferCall { to, amount: U256::from(amount) }.abi_encode();
+        self.respond(vm, calldata, success);
+    }
+}
+
+// Programs the ecrecover precompile to attribute PERMIT_SIGNATURE over `struct_hash` to `signer`
+fn sign_intent(vm: &TestVM, signer: Address, struct_hash: B256) {
+    let domain = utils::domain_separator(EIP712_NAME, EIP712_VERSION, vm.chain_id(), CONTRACT_ADDR);
+    let digest = utils::typed_data_digest(domain, struct_hash);
+    let (v, r, s) = PERMIT_SIGNATURE;
+    let input = (digest, U256::from(v), r, s).abi_encode();
+    vm.mock_static_call(Address::with_last_byte(1), input, Ok(signer.into_word().to_vec()));
+}
+
+fn deposit(vm: &TestVM, contract: &mut SubscriptionEscrow, user: Address, amount: u64) {
+    vm.set_sender(user);
+    vm.set_value(U256::from(amount));
+    contract.deposit().unwrap();
+    vm.set_value(U256::ZERO);
+
+    // The test VM does not move msg.value, so fund the contract by hand.
+    let held = vm.balance(CONTRACT_ADDR);
+    vm.set_balance(CONTRACT_ADDR, held + U256::from(amount));
+}
+
+#[test]
+fn test_initialization() {
+    let (vm, mut contract) = setup_contract();
+    assert_eq!(contract.get_admin(), ADMIN_ADDR);
+    assert_eq!(contract.get_treasury(), ADMIN_ADDR);
+    assert_eq!(contract.get_protocol_fee(), U256::from(DEFAULT_PROTOCOL_FEE_BPS));
+
+    // A second initialization must be rejected
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.constructor(USER_ADDR, USER_ADDR, U256::ZERO, Address::ZERO),
+        Err(SubscriptionError::AlreadyInitialized(_))
+    ));
+    assert_eq!(contract.get_admin(), ADMIN_ADDR);
+}
+
+#[test]
+fn test_constructor_takes_explicit_parameters() {
+    let vm = TestVM::default();
+    let treasury = Address::repeat_byte(0x7e);
+    let keeper = Address::repeat_byte(0x4b);
+    let mut contract = SubscriptionEscrow::from(&vm);
+
+    assert!(matches!(
+        contract.constructor(Address::ZERO, treasury, U256::ZERO, keeper),
+        Err(SubscriptionError::ZeroAddress(_))
+    ));
+    assert!(matches!(
+        contract.constructor(ADMIN_ADDR, treasury, U256::from(MAX_PROTOCOL_FEE_BPS + 1), keeper),
+        Err(SubscriptionError::FeeTooHigh(_))
+    ));
+
+    // The deployer does not have to be the admin
+    vm.set_sender(Address::repeat_byte(0xde));
+    contract.constructor(ADMIN_ADDR, treasury, U256::from(100), keeper).unwrap();
+    assert_eq!(contract.get_admin(), ADMIN_ADDR);
+    assert_eq!(contract.get_treasury(), treasury);
+    assert_eq!(contract.get_protocol_fee(), U256::from(100));
+    assert!(contract.is_keeper(keeper));
+}
+
+#[test]
+fn test_two_step_admin_transfer() {
+    let (vm, mut contract) = setup_contract();
+    let new_admin = Address::repeat_byte(0x0a);
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.transfer_admin(USER_ADDR), Err(SubscriptionError::MissingRole(_))));
+
+    vm.set_sender(ADMIN_ADDR);
+    contract.transfer_admin(new_admin).unwrap();
+    assert_eq!(contract.get_pending_admin(), new_admin);
+    assert_eq!(contract.get_admin(), ADMIN_ADDR);
+
+    // Only the pending admin can complete the handover
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.accept_admin(), Err(SubscriptionError::Unauthorized(_))));
+    vm.set_sender(new_admin);
+    contract.accept_admin().unwrap();
+    assert_eq!(contract.get_admin(), new_admin);
+    assert_eq!(contract.get_pending_admin(), Address::ZERO);
+    assert!(contract.has_role(ADMIN_ROLE, new_admin));
+    assert!(!contract.has_role(ADMIN_ROLE, ADMIN_ADDR));
+    assert!(contract.has_role(FEE_MANAGER_ROLE, new_admin));
+    assert!(!contract.has_role(FEE_MANAGER_ROLE, ADMIN_ADDR));
+    assert!(contract.accept_admin().is_err());
+
+    vm.set_sender(ADMIN_ADDR);
+    assert!(contract.set_treasury(ADMIN_ADDR).is_err());
+    assert!(matches!(contract.set_protocol_fee(U256::from(100)), Err(SubscriptionError::MissingRole(_))));
+
+    // Renouncing leaves the contract without an admin, and it cannot be re-initialized
+    vm.set_sender(new_admin);
+    contract.renounce_admin().unwrap();
+    assert_eq!(contract.get_admin(), Address::ZERO);
+    assert!(!contract.has_role(ADMIN_ROLE, new_admin));
+    assert!(!contract.has_role(FEE_MANAGER_ROLE, new_admin));
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.constructor(USER_ADDR, USER_ADDR, U256::ZERO, Address::ZERO),
+        Err(SubscriptionError::AlreadyInitialized(_))
+    ));
+}
+
+#[test]
+fn test_roles_gate_privileged_entrypoints() {
+    let (vm, mut contract) = setup_contract();
+    let fee_manager = Address::repeat_byte(0xfe);
+    let verifier = Address::repeat_byte(0x7f);
+    assert!(contract.has_role(ADMIN_ROLE, ADMIN_ADDR));
+    assert_eq!(contract.get_role_admin(FEE_MANAGER_ROLE), ADMIN_ROLE);
+
+    vm.set_sender(fee_manager);
+    assert!(matches!(
+        contract.set_protocol_fee(U256::from(100)),
+        Err(SubscriptionError::MissingRole(_))
+    ));
+    assert!(matches!(
+        contract.grant_role(FEE_MANAGER_ROLE, fee_manager),
+        Err(SubscriptionError::MissingRole(_))
+    ));
+
+    vm.set_sender(ADMIN_ADDR);
+    assert!(contract.grant_role(FEE_MANAGER_ROLE, fee_manager).unwrap());
+    assert!(!contract.grant_role(FEE_MANAGER_ROLE, fee_manager).unwrap());
+    vm.set_sender(fee_manager);
+    contract.set_protocol_fee(U256::from(100)).unwrap();
+    assert!(contract.set_treasury(fee_manager).is_err());
+
+    // The admin role itself only moves through the two-step transfer
+    vm.set_sender(ADMIN_ADDR);
+    assert!(matches!(
+        contract.grant_role(ADMIN_ROLE, fee_manager),
+        Err(SubscriptionError::AdminRoleTransferOnly(_))
+    ));
+
+    // Delegating a role's administration to another role
+    contract.set_role_admin(PROVIDER_VERIFIER_ROLE, FEE_MANAGER_ROLE).unwrap();
+    assert_eq!(contract.get_role_admin(PROVIDER_VERIFIER_ROLE), FEE_MANAGER_ROLE);
+    vm.set_sender(fee_manager);
+    contract.grant_role(PROVIDER_VERIFIER_ROLE, verifier).unwrap();
+
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    assert!(contract.set_provider_verified(PROVIDER_ADDR, true).is_err());
+    vm.set_sender(verifier);
+    contract.set_provider_verified(PROVIDER_ADDR, true).unwrap();
+    assert!(contract.is_provider_verified(PROVIDER_ADDR));
+
+    // Members can give up their own role; keepers are plain KEEPER_ROLE members
+    contract.renounce_role(PROVIDER_VERIFIER_ROLE).unwrap();
+    assert!(!contract.has_role(PROVIDER_VERIFIER_ROLE, verifier));
+    vm.set_sender(ADMIN_ADDR);
+    contract.add_keeper(verifier).unwrap();
+    assert!(contract.has_role(KEEPER_ROLE, verifier));
+    contract.revoke_role(KEEPER_ROLE, verifier).unwrap();
+    assert!(!contract.is_keeper(verifier));
+}
+
+#[test]
+fn test_provider_registration_validation() {
+    let (vm, mut contract) = setup_contract();
+
+    vm.set_sender(PROVIDER_ADDR);
+    assert!(contract.register_provider("x".repeat(101)).is_err());
+    assert!(contract.register_provider("Provider".into()).is_ok());
+    assert!(contract.is_provider_registered(PROVIDER_ADDR));
+
+    // Duplicate registration
+    assert!(contract.register_provider("Provider".into()).is_err());
+
+    // Unregistered callers cannot create plans
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.create_plan(U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL)),
+        Err(SubscriptionError::ProviderNotRegistered(_))
+    ));
+}
+
+#[test]
+fn test_provider_profile_and_payout_address() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+
+    let (name, metadata_uri, payout_address, registered_at, verified) = contract.get_provider(PROVIDER_ADDR);
+    assert_eq!(name, "Provider");
+    assert!(metadata_uri.is_empty());
+    assert_eq!(payout_address, PROVIDER_ADDR);
+    assert_eq!(registered_at, U256::from(START_TIME));
+    assert!(!verified);
+
+    let payout = Address::repeat_byte(0x9a);
+    let uri = String::from("ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi");
+    assert!(matches!(
+        contract.update_provider_profile("Renamed".into(), "x".repeat(257), payout),
+        Err(SubscriptionError::MetadataUriTooLong(_))
+    ));
+    assert!(matches!(
+        contract.update_provider_profile("Renamed".into(), uri.clone(), Address::ZERO),
+        Err(SubscriptionError::ZeroAddress(_))
+    ));
+    contract.update_provider_profile("Renamed".into(), uri.clone(), payout).unwrap();
+
+    let (name, metadata_uri, payout_address, registered_at, _) = contract.get_provider(PROVIDER_ADDR);
+    assert_eq!((name.as_str(), metadata_uri, payout_address), ("Renamed", uri, payout));
+    assert_eq!(registered_at, U256::from(START_TIME));
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.update_provider_profile("User".into(), String::new(), USER_ADDR),
+        Err(SubscriptionError::ProviderNotRegistered(_))
+    ));
+
+    // Earnings follow the payout address, not the signing key
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    contract.subscribe(plan_id).unwrap();
+    vm.set_sender(PROVIDER_ADDR);
+    contract.withdraw_provider_earnings().unwrap();
+    assert_eq!(vm.balance(payout), U256::from(975));
+    assert_eq!(vm.balance(PROVIDER_ADDR), U256::ZERO);
+}
+
+#[test]
+fn test_subscribe_charges_escrow() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.subscribe(plan_id),
+        Err(SubscriptionError::InsufficientEscrow(_))
+    ));
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    assert_eq!(subscription_id, U256::from(1));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(4_000));
+
+    // 2.5% protocol fee is kept back from the provider
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::from(975));
+}
+
+#[test]
+fn test_withdraw_escrow() {
+    let (vm, mut contract) = setup_contract();
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+
+    vm.set_sender(USER_ADDR);
+    assert!(contract.withdraw_escrow(U256::ZERO).is_err());
+    assert!(matches!(
+        contract.withdraw_escrow(U256::from(5_001)),
+        Err(SubscriptionError::InsufficientEscrow(_))
+    ));
+
+    let logs_before = vm.get_emitted_logs().len();
+    assert!(contract.withdraw_escrow(U256::from(2_000)).unwrap());
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(3_000));
+    assert_eq!(vm.balance(USER_ADDR), U256::from(2_000));
+    assert_eq!(vm.get_emitted_logs().len(), logs_before + 1);
+}
+
+#[test]
+fn test_withdraw_escrow_rolls_back_on_failed_transfer() {
+    let (vm, mut contract) = setup_contract();
+    vm.set_sender(USER_ADDR);
+    vm.set_value(U256::from(5_000));
+    contract.deposit().unwrap();
+    vm.set_value(U256::ZERO);
+
+    // The contract holds no ETH in the test VM, so the transfer fails
+    assert!(contract.withdraw_escrow(U256::from(2_000)).is_err());
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(5_000));
+}
+
+#[test]
+fn test_plan_price_change_applies_after_notice_period() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 10_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.update_plan(plan_id, U256::from(2_000), U256::from(PLAN_INTERVAL)),
+        Err(SubscriptionError::Unauthorized(_))
+    ));
+    vm.set_sender(PROVIDER_ADDR);
+    assert!(contract.update_plan(plan_id, U256::ZERO, U256::from(PLAN_INTERVAL)).is_err());
+    let effective_at = contract
+        .update_plan(plan_id, U256::from(2_000), U256::from(2 * PLAN_INTERVAL))
+        .unwrap();
+    assert_eq!(effective_at, U256::from(START_TIME + PLAN_CHANGE_NOTICE_PERIOD));
+
+    // The renewal inside the notice period is still charged the old price
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(8_000));
+    assert_eq!(contract.get_plan(plan_id).1, U256::from(PLAN_PRICE));
+
+    // The first renewal after the notice period uses the new terms
+    vm.set_block_timestamp(START_TIME + PLAN_CHANGE_NOTICE_PERIOD);
+    assert_eq!(
+        contract.get_plan(plan_id),
+        (PROVIDER_ADDR, U256::from(2_000), U256::from(2 * PLAN_INTERVAL), true)
+    );
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(6_000));
+    assert_eq!(
+        contract.get_subscription_next_due(subscription_id),
+        U256::from(START_TIME + PLAN_CHANGE_NOTICE_PERIOD + 2 * PLAN_INTERVAL)
+    );
+    assert_eq!(contract.get_pending_plan_change(plan_id), (U256::ZERO, U256::ZERO, U256::ZERO));
+}
+
+#[test]
+fn test_deactivated_plan_keeps_existing_subscriptions() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    assert!(contract.deactivate_plan(plan_id).is_err());
+    vm.set_sender(PROVIDER_ADDR);
+    contract.deactivate_plan(plan_id).unwrap();
+    assert!(matches!(contract.deactivate_plan(plan_id), Err(SubscriptionError::PlanInactive(_))));
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.subscribe(plan_id), Err(SubscriptionError::PlanInactive(_))));
+
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert!(contract.process_subscription_payment(subscription_id).unwrap());
+
+    vm.set_sender(PROVIDER_ADDR);
+    contract.reactivate_plan(plan_id).unwrap();
+    assert!(matches!(contract.reactivate_plan(plan_id), Err(SubscriptionError::PlanAlreadyActive(_))));
+    vm.set_sender(USER_ADDR);
+    contract.subscribe(plan_id).unwrap();
+}
+
+#[test]
+fn test_token_plan_uses_per_token_balances() {
+    let (vm, mut contract) = setup_contract();
+    let token = MockErc20::new(&vm, &mut contract);
+    let keeper = Address::repeat_byte(0x4b);
+    contract.add_keeper(keeper).unwrap();
+
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    assert!(matches!(
+        contract.create_token_plan(Address::repeat_byte(0xee), U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL)),
+        Err(SubscriptionError::TokenNotAllowed(_))
+    ));
+    let plan_id = contract
+        .create_token_plan(token.address, U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap();
+    assert_eq!(contract.get_plan_token(plan_id), token.address);
+
+    // A rejected pull credits nothing
+    vm.set_sender(USER_ADDR);
+    token.expect_transfer_from(&vm, USER_ADDR, 3_000, false);
+    assert!(matches!(
+        contract.deposit_token(token.address, U256::from(3_000)),
+        Err(SubscriptionError::TokenPullFailed(_))
+    ));
+    token.expect_transfer_from(&vm, USER_ADDR, 3_000, true);
+    contract.deposit_token(token.address, U256::from(3_000)).unwrap();
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::from(3_000));
+
+    // An allow-listed address without code answers every call with empty data
+    let codeless = Address::repeat_byte(0xe3);
+    vm.set_sender(ADMIN_ADDR);
+    contract.set_token_allowed(codeless, true).unwrap();
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.deposit_token(codeless, U256::from(3_000)),
+        Err(SubscriptionError::TokenPullFailed(_))
+    ));
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, codeless), U256::ZERO);
+
+    // ETH escrow does not fund token plans
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(5_000));
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::from(2_000));
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    vm.set_sender(keeper);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_provider_token_earnings(PROVIDER_ADDR, token.address), U256::from(975 + 975));
+    assert_eq!(contract.get_protocol_token_treasury_balance(token.address), U256::from(50));
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::ZERO);
+    assert_eq!(contract.get_token_value_locked(token.address), U256::from(3_000));
+
+    // Withdrawals pay out in the plan's token and roll back if the token refuses
+    vm.set_sender(PROVIDER_ADDR);
+    token.expect_transfer(&vm, PROVIDER_ADDR, 1_950, false);
+    assert!(matches!(
+        contract.withdraw_provider_token_earnings(token.address),
+        Err(SubscriptionError::TransferFailed(_))
+    ));
+    assert_eq!(contract.get_provider_token_earnings(PROVIDER_ADDR, token.address), U256::from(1_950));
+    token.expect_transfer(&vm, PROVIDER_ADDR, 1_950, true);
+    contract.withdraw_provider_token_earnings(token.address).unwrap();
+
+    vm.set_sender(USER_ADDR);
+    token.expect_transfer(&vm, USER_ADDR, 1_000, true);
+    contract.withdraw_token_escrow(token.address, U256::from(1_000)).unwrap();
+
+    vm.set_sender(ADMIN_ADDR);
+    token.expect_transfer(&vm, ADMIN_ADDR, 50, true);
+    contract.withdraw_protocol_token_fees(token.address, Address::ZERO, U256::from(50)).unwrap();
+    assert_eq!(contract.get_token_value_locked(token.address), U256::ZERO);
+    assert_eq!(contract.get_total_value_locked(), U256::from(5_000));
+
+    // Delisting blocks new plans and subscriptions but not renewals
+    contract.set_token_allowed(token.address, false).unwrap();
+    assert!(!contract.is_token_allowed(token.address));
+    assert!(contract.is_token_allowed(Address::ZERO));
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.subscribe(plan_id), Err(SubscriptionError::TokenNotAllowed(_))));
+}
+
+#[test]
+fn test_allowance_funded_renewals_pull_from_wallet() {
+    let (vm, mut contract) = setup_contract();
+    let token = MockErc20::new(&vm, &mut contract);
+    let native_plan = setup_plan(&vm, &mut contract);
+    let plan_id = contract
+        .create_token_plan(token.address, U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap();
+    contract.set_plan_grace_period(plan_id, U256::from(3_600)).unwrap();
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.subscribe_with_allowance(native_plan),
+        Err(SubscriptionError::AllowanceRequiresToken(_))
+    ));
+    token.expect_transfer_from(&vm, USER_ADDR, PLAN_PRICE, false);
+    assert!(matches!(
+        contract.subscribe_with_allowance(plan_id),
+        Err(SubscriptionError::TokenPullFailed(_))
+    ));
+
+    // Nothing is prefunded: each charge is pulled straight from the wallet
+    token.expect_transfer_from(&vm, USER_ADDR, PLAN_PRICE, true);
+    let subscription_id = contract.subscribe_with_allowance(plan_id).unwrap();
+    assert!(contract.is_allowance_funded(subscription_id));
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::ZERO);
+    assert_eq!(contract.get_provider_token_earnings(PROVIDER_ADDR, token.address), U256::from(975));
+
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert!(contract.process_subscription_payment(subscription_id).unwrap());
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::ZERO);
+    assert_eq!(contract.get_token_value_locked(token.address), U256::from(2_000));
+
+    // A refused pull goes through the same dunning path as an empty escrow
+    token.expect_transfer_from(&vm, USER_ADDR, PLAN_PRICE, false);
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL);
+    assert!(!contract.process_subscription_payment(subscription_id).unwrap());
+    assert_eq!(contract.get_subscription_status(subscription_id), SubscriptionStatus::PastDue as u8);
+
+    token.expect_transfer_from(&vm, USER_ADDR, PLAN_PRICE, true);
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL + 3_600);
+    assert!(contract.process_subscription_payment(subscription_id).unwrap());
+    assert!(contract.is_subscription_active(subscription_id));
+
+    assert!(matches!(
+        contract.set_allowance_funding(subscription_id, false),
+        Err(SubscriptionError::Unauthorized(_))
+    ));
+    vm.set_sender(USER_ADDR);
+    contract.set_allowance_funding(subscription_id, false).unwrap();
+    assert!(!contract.is_allowance_funded(subscription_id));
+}
+
+#[test]
+fn test_subscribe_with_permit_prefunds_in_one_call() {
+    let (vm, mut contract) = setup_contract();
+    let token = MockErc20::new(&vm, &mut contract);
+    let native_plan = setup_plan(&vm, &mut contract);
+    let plan_id = contract
+        .create_token_plan(token.address, U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap();
+    let deadline = START_TIME + 600;
+    let (v, r, s) = PERMIT_SIGNATURE;
+
+    vm.set_sender(USER_ADDR);
+    let amount = U256::from(3_000);
+    assert!(matches!(
+        contract.subscribe_with_permit(native_plan, amount, U256::from(deadline), v, r, s),
+        Err(SubscriptionError::AllowanceRequiresToken(_))
+    ));
+    assert!(matches!(
+        contract.subscribe_with_permit(plan_id, U256::from(999), U256::from(deadline), v, r, s),
+        Err(SubscriptionError::PermitAmountTooLow(_))
+    ));
+    token.expect_permit(&vm, USER_ADDR, 3_000, deadline, false);
+    assert!(matches!(
+        contract.subscribe_with_permit(plan_id, amount, U256::from(deadline), v, r, s),
+        Err(SubscriptionError::PermitFailed(_))
+    ));
+
+    // First charge plus 2_000 of prefund, pulled under the permit
+    token.expect_permit(&vm, USER_ADDR, 3_000, deadline, true);
+    token.expect_transfer_from(&vm, USER_ADDR, 3_000, true);
+    let subscription_id = contract
+        .subscribe_with_permit(plan_id, amount, U256::from(deadline), v, r, s)
+        .unwrap();
+    assert!(contract.is_subscription_active(subscription_id));
+    assert!(!contract.is_allowance_funded(subscription_id));
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::from(2_000));
+    assert_eq!(contract.get_provider_token_earnings(PROVIDER_ADDR, token.address), U256::from(975));
+    assert_eq!(contract.get_token_value_locked(token.address), U256::from(3_000));
+}
+
+#[test]
+fn test_subscribe_with_permit_uses_existing_allowance_when_permit_fails() {
+    let (vm, mut contract) = setup_contract();
+    let token = MockErc20::new(&vm, &mut contract);
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    let plan_id = contract
+        .create_token_plan(token.address, U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap();
+    let deadline = START_TIME + 600;
+    let (v, r, s) = PERMIT_SIGNATURE;
+
+    // The permit was already submitted by someone else, so its nonce is spent
+    vm.set_sender(USER_ADDR);
+    token.expect_permit(&vm, USER_ADDR, 3_000, deadline, false);
+    token.set_allowance(&vm, USER_ADDR, 2_999);
+    assert!(matches!(
+        contract.subscribe_with_permit(plan_id, U256::from(3_000), U256::from(deadline), v, r, s),
+        Err(SubscriptionError::PermitFailed(_))
+    ));
+
+    token.set_allowance(&vm, USER_ADDR, 3_000);
+    token.expect_transfer_from(&vm, USER_ADDR, 3_000, true);
+    let subscription_id = contract
+        .subscribe_with_permit(plan_id, U256::from(3_000), U256::from(deadline), v, r, s)
+        .unwrap();
+    assert!(contract.is_subscription_active(subscription_id));
+    assert_eq!(contract.get_user_token_balance(USER_ADDR, token.address), U256::from(2_000));
+}
+
+#[test]
+fn test_relayed_intents_act_for_the_signer() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    let relayer = Address::repeat_byte(0x77);
+    let deadline = U256::from(START_TIME + 600);
+    let (v, r, s) = PERMIT_SIGNATURE;
+
+    let subscribe_hash = keccak((B256::from(SUBSCRIBE_TYPEHASH), USER_ADDR, plan_id, U256::ZERO, deadline).abi_encode());
+    sign_intent(&vm, USER_ADDR, subscribe_hash);
+
+    vm.set_sender(relayer);
+    // The same signature does not authorise a different account
+    assert!(matches!(
+        contract.subscribe_by_sig(Address::repeat_byte(0x04), plan_id, deadline, v, r, s),
+        Err(SubscriptionError::InvalidSignature(_))
+    ));
+    let subscription_id = contract.subscribe_by_sig(USER_ADDR, plan_id, deadline, v, r, s).unwrap();
+    assert_eq!(contract.get_user_subscriptions(USER_ADDR), vec![subscription_id]);
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(4_000));
+    assert_eq!(contract.get_nonce(USER_ADDR), U256::from(1));
+
+    // Replays fail because the nonce has moved on
+    assert!(matches!(
+        contract.subscribe_by_sig(USER_ADDR, plan_id, deadline, v, r, s),
+        Err(SubscriptionError::InvalidSignature(_))
+    ));
+
+    let cancel_hash = keccak(
+        (B256::from(CANCEL_TYPEHASH), USER_ADDR, subscription_id, U256::from(1), deadline).abi_encode(),
+    );
+    sign_intent(&vm, USER_ADDR, cancel_hash);
+    vm.set_block_timestamp(START_TIME + 601);
+    assert!(matches!(
+        contract.cancel_subscription_by_sig(USER_ADDR, subscription_id, deadline, v, r, s),
+        Err(SubscriptionError::SignatureExpired(_))
+    ));
+    vm.set_block_timestamp(START_TIME + 600);
+    contract.cancel_subscription_by_sig(USER_ADDR, subscription_id, deadline, v, r, s).unwrap();
+    assert!(!contract.is_subscription_active(subscription_id));
+
+    let amount = U256::from(4_000);
+    let withdraw_hash = keccak(
+        (B256::from(WITHDRAW_ESCROW_TYPEHASH), USER_ADDR, Address::ZERO, amount, U256::from(2), deadline).abi_encode(),
+    );
+    sign_intent(&vm, USER_ADDR, withdraw_hash);
+    contract.withdraw_escrow_by_sig(USER_ADDR, Address::ZERO, amount, deadline, v, r, s).unwrap();
+    assert_eq!(vm.balance(USER_ADDR), amount);
+    assert_eq!(vm.balance(relayer), U256::ZERO);
+    assert_eq!(contract.get_nonce(USER_ADDR), U256::from(3));
+}
+
+#[test]
+fn test_cancel_subscription_prorated_refund() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    contract
+        .set_plan_refund_policy(plan_id, RefundPolicy::Prorated as u8, U256::ZERO)
+        .unwrap();
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    // Outsiders cannot cancel
+    vm.set_sender(ADMIN_ADDR);
+    assert!(matches!(
+        contract.cancel_subscription(subscription_id),
+        Err(SubscriptionError::Unauthorized(_))
+    ));
+
+    // Half of the interval unused -> half of the provider share comes back
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL / 2);
+    vm.set_sender(USER_ADDR);
+    let refund = contract.cancel_subscription(subscription_id).unwrap();
+    assert_eq!(refund, U256::from(487));
+    assert!(!contract.is_subscription_active(subscription_id));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(4_487));
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::from(975 - 487));
+
+    assert!(contract.cancel_subscription(subscription_id).is_err());
+}
+
+#[test]
+fn test_cancel_subscription_cooling_off_refund() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    contract
+        .set_plan_refund_policy(plan_id, RefundPolicy::CoolingOff as u8, U256::from(3_600))
+        .unwrap();
+    assert!(contract.set_plan_refund_policy(plan_id, 3, U256::ZERO).is_err());
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let inside_window = contract.subscribe(plan_id).unwrap();
+    let outside_window = contract.subscribe(plan_id).unwrap();
+
+    vm.set_block_timestamp(START_TIME + 1_800);
+    assert_eq!(contract.cancel_subscription(inside_window).unwrap(), U256::from(975));
+
+    // The provider may also cancel, but the window has closed by now
+    vm.set_block_timestamp(START_TIME + 7_200);
+    vm.set_sender(PROVIDER_ADDR);
+    assert_eq!(contract.cancel_subscription(outside_window).unwrap(), U256::ZERO);
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(3_975));
+}
+
+#[test]
+fn test_protocol_fees_accrue_to_treasury() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    vm.set_sender(ADMIN_ADDR);
+    contract.process_subscription_payment(subscription_id).unwrap();
+
+    assert_eq!(contract.get_protocol_treasury_balance(), U256::from(50));
+    assert_eq!(
+        contract.get_total_value_locked(),
+        contract.get_user_balance(USER_ADDR)
+            + contract.get_provider_earnings(PROVIDER_ADDR)
+            + contract.get_protocol_treasury_balance()
+    );
+}
+
+#[test]
+fn test_withdraw_protocol_fees() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    contract.subscribe(plan_id).unwrap();
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.withdraw_protocol_fees(USER_ADDR, U256::from(25)),
+        Err(SubscriptionError::MissingRole(_))
+    ));
+
+    let treasury = Address::repeat_byte(0x7e);
+    vm.set_sender(ADMIN_ADDR);
+    contract.set_treasury(treasury).unwrap();
+    assert!(contract.withdraw_protocol_fees(Address::ZERO, U256::from(26)).is_err());
+
+    // A zero recipient falls back to the configured treasury
+    contract.withdraw_protocol_fees(Address::ZERO, U256::from(25)).unwrap();
+    assert_eq!(vm.balance(treasury), U256::from(25));
+    assert_eq!(contract.get_protocol_treasury_balance(), U256::ZERO);
+    assert_eq!(contract.get_total_value_locked(), U256::from(4_975));
+}
+
+#[test]
+fn test_protocol_fee_change_is_timelocked() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+
+    vm.set_sender(USER_ADDR);
+    assert!(contract.set_protocol_fee(U256::from(500)).is_err());
+
+    vm.set_sender(ADMIN_ADDR);
+    assert!(contract
+        .set_protocol_fee(U256::from(MAX_PROTOCOL_FEE_BPS + 1))
+        .is_err());
+    assert!(matches!(
+        contract.set_protocol_fee_delay(U256::from(MIN_FEE_CHANGE_DELAY - 1)),
+        Err(SubscriptionError::FeeDelayTooShort(_))
+    ));
+    contract.set_protocol_fee_delay(U256::from(MIN_FEE_CHANGE_DELAY)).unwrap();
+    contract.set_protocol_fee(U256::from(500)).unwrap();
+    assert_eq!(
+        contract.get_pending_protocol_fee(),
+        (U256::from(500), U256::from(START_TIME + MIN_FEE_CHANGE_DELAY))
+    );
+
+    // The old fee still applies until the delay has passed
+    assert!(contract.apply_protocol_fee_change().is_err());
+    vm.set_sender(USER_ADDR);
+    contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_protocol_treasury_balance(), U256::from(25));
+
+    vm.set_block_timestamp(START_TIME + MIN_FEE_CHANGE_DELAY);
+    assert_eq!(contract.get_protocol_fee(), U256::from(500));
+    contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_protocol_treasury_balance(), U256::from(75));
+    assert_eq!(contract.get_pending_protocol_fee(), (U256::ZERO, U256::ZERO));
+}
+
+#[test]
+fn test_keepers_process_payments() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+
+    let keeper = Address::repeat_byte(0x4b);
+    vm.set_sender(keeper);
+    assert!(matches!(
+        contract.process_subscription_payment(subscription_id),
+        Err(SubscriptionError::MissingRole(_))
+    ));
+    assert!(contract.add_keeper(keeper).is_err());
+
+    vm.set_sender(ADMIN_ADDR);
+    contract.add_keeper(keeper).unwrap();
+    assert!(contract.is_keeper(keeper));
+    vm.set_sender(keeper);
+    contract.process_subscription_payment(subscription_id).unwrap();
+
+    vm.set_sender(ADMIN_ADDR);
+    contract.remove_keeper(keeper).unwrap();
+    assert!(contract.remove_keeper(keeper).is_err());
+
+    // Permissionless mode lets anyone trigger a due payment, but not an early one
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL - 1);
+    contract.set_permissionless_processing(true).unwrap();
+    vm.set_sender(Address::repeat_byte(0x99));
+    assert!(contract.process_subscription_payment(subscription_id).is_err());
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(2_000));
+}
+
+#[test]
+fn test_guardian_pause_blocks_payments_but_not_exits() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    let guardian = Address::repeat_byte(0x6a);
+    vm.set_sender(ADMIN_ADDR);
+    contract.grant_role(GUARDIAN_ROLE, guardian).unwrap();
+
+    // Only the guardian can pause, not even the admin
+    assert!(matches!(contract.pause(), Err(SubscriptionError::MissingRole(_))));
+    vm.set_sender(guardian);
+    contract.pause().unwrap();
+    assert!(contract.is_paused());
+    assert!(matches!(contract.pause(), Err(SubscriptionError::ContractPaused(_))));
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.subscribe(plan_id), Err(SubscriptionError::ContractPaused(_))));
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert!(!contract.checker(USER_ADDR).0);
+    vm.set_sender(ADMIN_ADDR);
+    assert!(matches!(
+        contract.process_subscription_payment(subscription_id),
+        Err(SubscriptionError::ContractPaused(_))
+    ));
+    vm.set_sender(PROVIDER_ADDR);
+    assert!(matches!(contract.withdraw_provider_earnings(), Err(SubscriptionError::ContractPaused(_))));
+
+    // Subscribers can still leave with their funds
+    vm.set_sender(USER_ADDR);
+    contract.cancel_subscription(subscription_id).unwrap();
+    assert!(contract.withdraw_escrow(U256::from(4_000)).unwrap());
+
+    // Unpausing is reserved for the admin
+    vm.set_sender(guardian);
+    assert!(matches!(contract.unpause(), Err(SubscriptionError::MissingRole(_))));
+    vm.set_sender(ADMIN_ADDR);
+    contract.unpause().unwrap();
+    assert!(!contract.is_paused());
+    assert!(matches!(contract.unpause(), Err(SubscriptionError::ContractNotPaused(_))));
+
+    vm.set_sender(PROVIDER_ADDR);
+    assert!(contract.withdraw_provider_earnings().unwrap());
+}
+
+#[test]
+fn test_keeper_fee_on_renewal() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let keeper = Address::repeat_byte(0x4b);
+
+    vm.set_sender(ADMIN_ADDR);
+    contract.add_keeper(keeper).unwrap();
+    assert!(contract
+        .set_keeper_fee(U256::from(100), U256::from(60), U256::from(50))
+        .is_err());
+    // 1% of the price is 10 wei, clamped up to the 20 wei minimum
+    contract
+        .set_keeper_fee(U256::from(100), U256::from(20), U256::from(50))
+        .unwrap();
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::from(975));
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    vm.set_sender(keeper);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_keeper_earnings(keeper), U256::from(20));
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::from(975 + 955));
+    assert_eq!(
+        contract.get_total_value_locked(),
+        contract.get_user_balance(USER_ADDR)
+            + contract.get_provider_earnings(PROVIDER_ADDR)
+            + contract.get_protocol_treasury_balance()
+            + contract.get_keeper_earnings(keeper)
+    );
+
+    vm.set_balance(CONTRACT_ADDR, U256::from(5_000));
+    contract.withdraw_keeper_earnings().unwrap();
+    assert_eq!(vm.balance(keeper), U256::from(20));
+    assert!(contract.withdraw_keeper_earnings().is_err());
+}
+
+#[test]
+fn test_keeper_fee_bounds_are_per_token() {
+    let (vm, mut contract) = setup_contract();
+    let token = MockErc20::new(&vm, &mut contract);
+    let keeper = Address::repeat_byte(0x4b);
+    contract.add_keeper(keeper).unwrap();
+    contract
+        .set_keeper_fee(U256::from(100), U256::from(20), U256::from(50))
+        .unwrap();
+    assert!(matches!(
+        contract.set_token_keeper_fee(Address::repeat_byte(0xee), U256::ZERO, U256::from(8)),
+        Err(SubscriptionError::TokenNotAllowed(_))
+    ));
+    assert!(matches!(
+        contract.set_token_keeper_fee(token.address, U256::from(9), U256::from(8)),
+        Err(SubscriptionError::InvalidFeeRange(_))
+    ));
+
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    let plan_id = contract
+        .create_token_plan(token.address, U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL))
+        .unwrap();
+    vm.set_sender(USER_ADDR);
+    token.expect_transfer_from(&vm, USER_ADDR, 3_000, true);
+    contract.deposit_token(token.address, U256::from(3_000)).unwrap();
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    // The ETH clamps do not apply to token payments, and an unset token pays keepers nothing
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    vm.set_sender(keeper);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_keeper_token_earnings(keeper, token.address), U256::ZERO);
+
+    // 1% of the price is 10 token units, clamped down to the token's 8 unit maximum
+    vm.set_sender(ADMIN_ADDR);
+    contract
+        .set_token_keeper_fee(token.address, U256::from(5), U256::from(8))
+        .unwrap();
+    assert_eq!(contract.get_token_keeper_fee(token.address), (U256::from(5), U256::from(8)));
+    assert_eq!(contract.get_token_keeper_fee(Address::ZERO), (U256::from(20), U256::from(50)));
+
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL);
+    vm.set_sender(keeper);
+    contract.process_subscription_payment(subscription_id).unwrap();
+    assert_eq!(contract.get_keeper_token_earnings(keeper, token.address), U256::from(8));
+    assert_eq!(contract.get_keeper_earnings(keeper), U256::ZERO);
+    assert_eq!(
+        contract.get_provider_token_earnings(PROVIDER_ADDR, token.address),
+        U256::from(975 + 975 + 967)
+    );
+}
+
+#[test]
+fn test_process_payments_batch_skips_failures() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let other_user = Address::repeat_byte(0x04);
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    deposit(&vm, &mut contract, other_user, 1_000);
+    vm.set_sender(USER_ADDR);
+    let funded = contract.subscribe(plan_id).unwrap();
+    vm.set_sender(other_user);
+    let unfunded = contract.subscribe(plan_id).unwrap();
+
+    vm.set_sender(ADMIN_ADDR);
+    assert!(contract.process_payments_batch(vec![]).is_err());
+    assert!(contract
+        .process_payments_batch(vec![funded; MAX_BATCH_SIZE + 1])
+        .is_err());
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    let status = contract
+        .process_payments_batch(vec![unfunded, funded, U256::from(99), funded])
+        .unwrap();
+
+    // Only the first attempt on the funded subscription is charged; the repeat is not due
+    // and the unfunded renewal is recorded as past due instead of reverting
+    assert_eq!(status, U256::from(0b0010));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(3_000));
+    assert_eq!(contract.get_subscription_status(unfunded), SubscriptionStatus::PastDue as u8);
+}
+
+#[test]
+fn test_failed_renewal_enters_grace_period_and_recovers_on_deposit() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let grace_period = 3 * 86_400;
+
+    assert!(matches!(
+        contract.set_plan_grace_period(plan_id, U256::from(MAX_GRACE_PERIOD + 1)),
+        Err(SubscriptionError::GracePeriodTooLong(_))
+    ));
+    contract.set_plan_grace_period(plan_id, U256::from(grace_period)).unwrap();
+
+    deposit(&vm, &mut contract, USER_ADDR, PLAN_PRICE);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_subscription_status(subscription_id), SubscriptionStatus::Active as u8);
+
+    let renewal_time = START_TIME + PLAN_INTERVAL;
+    vm.set_block_timestamp(renewal_time);
+    vm.set_sender(ADMIN_ADDR);
+    assert!(!contract.process_subscription_payment(subscription_id).unwrap());
+    assert_eq!(contract.get_subscription_status(subscription_id), SubscriptionStatus::PastDue as u8);
+    assert_eq!(contract.get_subscription_past_due_since(subscription_id), U256::from(renewal_time));
+    assert_eq!(
+        contract.get_subscription_next_due(subscription_id),
+        U256::from(renewal_time + grace_period)
+    );
+    assert!(matches!(
+        contract.process_subscription_payment(subscription_id),
+        Err(SubscriptionError::PaymentNotDue(_))
+    ));
+
+    // Topping up settles the overdue renewal straight away
+    vm.set_block_timestamp(renewal_time + 100);
+    deposit(&vm, &mut contract, USER_ADDR, 1_500);
+    assert!(contract.is_subscription_active(subscription_id));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(500));
+    assert_eq!(contract.get_subscription_past_due_since(subscription_id), U256::ZERO);
+    assert_eq!(
+        contract.get_subscription_next_due(subscription_id),
+        U256::from(renewal_time + 100 + PLAN_INTERVAL)
+    );
+}
+
+#[test]
+fn test_past_due_subscription_suspended_after_grace_period() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    contract.set_plan_grace_period(plan_id, U256::from(3_600)).unwrap();
+
+    deposit(&vm, &mut contract, USER_ADDR, PLAN_PRICE);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert!(!contract.process_subscription_payment(subscription_id).unwrap());
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL + 3_600);
+    let (can_exec, _) = contract.checker(USER_ADDR);
+    assert!(can_exec);
+    assert!(!contract.process_subscription_payment(subscription_id).unwrap());
+
+    assert_eq!(contract.get_subscription_status(subscription_id), SubscriptionStatus::Suspended as u8);
+    assert!(contract.next_due_subscriptions(U256::from(10)).is_empty());
+    assert!(matches!(
+        contract.process_subscription_payment(subscription_id),
+        Err(SubscriptionError::SubscriptionNotActive(_))
+    ));
+
+    // A suspended subscription is not revived by a later deposit
+    deposit(&vm, &mut contract, USER_ADDR, PLAN_PRICE);
+    assert_eq!(contract.get_subscription_status(subscription_id), SubscriptionStatus::Suspended as u8);
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(PLAN_PRICE));
+}
+
+#[test]
+fn test_fixed_term_subscription_expires_after_max_cycles() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    contract.set_plan_max_cycles(plan_id, U256::from(12)).unwrap();
+    deposit(&vm, &mut contract, USER_ADDR, 10_000);
+
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(
+        contract.subscribe_for_cycles(plan_id, U256::from(13)),
+        Err(SubscriptionError::InvalidCycleCount(_))
+    ));
+    let package = contract.subscribe_for_cycles(plan_id, U256::from(3)).unwrap();
+    let plan_default = contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_subscription_max_cycles(package), U256::from(3));
+    assert_eq!(contract.get_subscription_max_cycles(plan_default), U256::from(12));
+
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert!(contract.process_subscription_payment(package).unwrap());
+    assert_eq!(contract.get_subscription_status(package), SubscriptionStatus::Active as u8);
+
+    // Charging the third and last cycle expires the package, which stays usable until
+    // the period it paid for ends
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL);
+    assert!(contract.process_subscription_payment(package).unwrap());
+    assert_eq!(contract.get_subscription_payment_count(package), U256::from(3));
+    assert_eq!(contract.get_subscription_status(package), SubscriptionStatus::Expired as u8);
+    let (topics, data) = vm.get_emitted_logs().pop().unwrap();
+    let expired = SubscriptionExpired::decode_raw_log(topics, &data, true).unwrap();
+    assert_eq!(expired.subscriptionId, package);
+    assert_eq!(expired.paymentCount, U256::from(3));
+    assert_eq!(expired.endsAt, U256::from(START_TIME + 3 * PLAN_INTERVAL));
+
+    vm.set_block_timestamp(START_TIME + 3 * PLAN_INTERVAL - 1);
+    assert!(contract.is_subscription_active(package));
+    vm.set_block_timestamp(START_TIME + 3 * PLAN_INTERVAL);
+    assert!(!contract.is_subscription_active(package));
+
+    // Only the open-ended subscription is still queued
+
+    assert_eq!(contract.next_due_subscriptions(U256::from(10)), vec![plan_default]);
+    let (_, payload) = contract.checker(USER_ADDR);
+    assert_eq!(&payload[4..], &plan_default.to_be_bytes::<32>());
+    let (_, payload, _) = contract.checker_all(U256::ZERO, U256::from(10));
+    assert_eq!(&payload[4..], &plan_default.to_be_bytes::<32>());
+    let balance = contract.get_user_balance(USER_ADDR);
+    assert!(matches!(
+        contract.process_subscription_payment(package),
+        Err(SubscriptionError::SubscriptionNotActive(_))
+    ));
+    assert_eq!(contract.get_user_balance(USER_ADDR), balance);
+
+    // A single-cycle package is fully paid, and expires, on subscribe
+    vm.set_sender(USER_ADDR);
+    let single = contract.subscribe_for_cycles(plan_id, U256::from(1)).unwrap();
+    assert_eq!(contract.get_subscription_status(single), SubscriptionStatus::Expired as u8);
+    assert!(contract.is_subscription_active(single));
+    assert!(!contract.next_due_subscriptions(U256::from(10)).contains(&single));
+    contract.cancel_subscription(single).unwrap();
+    assert_eq!(contract.get_subscription_status(single), SubscriptionStatus::Cancelled as u8);
+}
+
+#[test]
+fn test_free_trial_defers_first_charge_once_per_plan() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let trial_duration = 7 * 86_400;
+    assert!(matches!(
+        contract.set_plan_trial_duration(plan_id, U256::from(MAX_TRIAL_DURATION + 1)),
+        Err(SubscriptionError::TrialTooLong(_))
+    ));
+    contract.set_plan_trial_duration(plan_id, U256::from(trial_duration)).unwrap();
+
+    // The balance is checked up front even though nothing is charged
+    vm.set_sender(USER_ADDR);
+    assert!(matches!(contract.subscribe(plan_id), Err(SubscriptionError::InsufficientEscrow(_))));
+    assert!(!contract.has_used_trial(plan_id, USER_ADDR));
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let trial = contract.subscribe(plan_id).unwrap();
+    assert!(contract.has_used_trial(plan_id, USER_ADDR));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(5_000));
+    assert_eq!(contract.get_provider_earnings(PROVIDER_ADDR), U256::ZERO);
+    assert_eq!(contract.get_subscription_payment_count(trial), U256::ZERO);
+    assert_eq!(contract.get_subscription_next_due(trial), U256::from(START_TIME + trial_duration));
+
+    // A second subscription to the same plan is charged straight away
+    let repeat = contract.subscribe(plan_id).unwrap();
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(4_000));
+    assert_eq!(contract.get_subscription_next_due(repeat), U256::from(START_TIME + PLAN_INTERVAL));
+    assert_eq!(contract.cancel_subscription(trial).unwrap(), U256::ZERO);
+
+    vm.set_sender(Address::repeat_byte(0x04));
+    deposit(&vm, &mut contract, Address::repeat_byte(0x04), 1_000);
+    let other = contract.subscribe(plan_id).unwrap();
+    vm.set_sender(ADMIN_ADDR);
+    vm.set_block_timestamp(START_TIME + trial_duration - 1);
+    assert!(contract.process_subscription_payment(other).is_err());
+    vm.set_block_timestamp(START_TIME + trial_duration);
+    assert!(contract.process_subscription_payment(other).unwrap());
+    assert_eq!(contract.get_user_balance(Address::repeat_byte(0x04)), U256::ZERO);
+    assert_eq!(contract.get_subscription_payment_count(other), U256::from(1));
+}
+
+#[test]
+fn test_due_queue_orders_by_next_due() {
+    let (vm, mut contract) = setup_contract();
+    vm.set_sender(PROVIDER_ADDR);
+    contract.register_provider("Provider".into()).unwrap();
+    let intervals = [500u64, 100, 400, 200, 300];
+    let mut plans = Vec::new();
+    for interval in intervals {
+        plans.push(
+            contract
+                .create_plan(U256::from(PLAN_PRICE), U256::from(interval))
+                .unwrap(),
         );
-        assert!(result.is_err());
-    }
-    
-    #[test]
-    fn test_subscription_status_conversion() {
-        use crate::utils::*;
-        
-        let status = SubscriptionStatus::from(0u8);
-        assert_eq!(status, SubscriptionStatus::Active);
-        
-        let status = SubscriptionStatus::from(1u8);
-        assert_eq!(status, SubscriptionStatus::PastDue);
-        
-        let status = SubscriptionStatus::from(2u8);
-        assert_eq!(status, SubscriptionStatus::CancelledByUser);
-        
-        let status = SubscriptionStatus::from(3u8);
-        assert_eq!(status, SubscriptionStatus::CancelledByProvider);
-        
-        let status = SubscriptionStatus::from(4u8);
-        assert_eq!(status, SubscriptionStatus::Expired);
-        
-        // Test fallback
-        let status = SubscriptionStatus::from(99u8);
-        assert_eq!(status, SubscriptionStatus::Active);
-    }
-    
-    #[test]
-    fn test_subscription_data_storage() {
-        let mut contract = setup_contract();
-        
-        // Simulate subscription creation (without msg context)
-        let sub_id = U256::from(1);
-        contract.sub_count.set(sub_id);
-        contract.sub_user.setter(sub_id).set(USER_ADDR);
-        contract.sub_provider.setter(sub_id).set(PROVIDER_ADDR);
-        contract.sub_amount_per_interval.setter(sub_id).set(U256::from(1000));
-        contract.sub_interval_seconds.setter(sub_id).set(300);
-        contract.sub_duration_intervals.setter(sub_id).set(12);
-        contract.sub_balance.setter(sub_id).set(U256::from(500));
-        contract.sub_paid_intervals.setter(sub_id).set(U256::from(1));
-        contract.sub_status.setter(sub_id).set(0); // Active
-        
-        // Test get_subscription
-        let (user, provider, amount, interval, duration, balance, paid, _, status, _) = 
-            contract.get_subscription(sub_id);
-        
-        assert_eq!(user, USER_ADDR);
-        assert_eq!(provider, PROVIDER_ADDR);
-        assert_eq!(amount, U256::from(1000));
-        assert_eq!(interval, 300);
-        assert_eq!(duration, 12);
-        assert_eq!(balance, U256::from(500));
-        assert_eq!(paid, U256::from(1));
-        assert_eq!(status, 0);
-    }
-    
-    #[test]
-    fn test_provider_balance_tracking() {
-        let mut contract = setup_contract();
-        
-        // Set provider balance
-        contract.provider_balances.setter(PROVIDER_ADDR).set(U256::from(5000));
-        
-        // Test get_provider_balance
-        let balance = contract.get_provider_balance(PROVIDER_ADDR);
-        assert_eq!(balance, U256::from(5000));
-        
-        // Test zero balance for unknown provider
-        let balance = contract.get_provider_balance(OTHER_ADDR);
-        assert_eq!(balance, U256::ZERO);
-    }
-    
-    #[test]
-    fn test_subscription_arrays() {
-        let mut contract = setup_contract();
-        
-        // Add subscription IDs to user and provider arrays
-        contract.user_subscriptions.setter(USER_ADDR).push(U256::from(1));
-        contract.user_subscriptions.setter(USER_ADDR).push(U256::from(3));
-        
-        contract.provider_subscriptions.setter(PROVIDER_ADDR).push(U256::from(1));
-        contract.provider_subscriptions.setter(PROVIDER_ADDR).push(U256::from(2));
-        
-        // Test retrieval
-        let user_subs = contract.get_user_subscriptions(USER_ADDR);
-        assert_eq!(user_subs.len(), 2);
-        assert_eq!(user_subs[0], U256::from(1));
-        assert_eq!(user_subs[1], U256::from(3));
-        
-        let provider_subs = contract.get_provider_subscriptions(PROVIDER_ADDR);
-        assert_eq!(provider_subs.len(), 2);
-        assert_eq!(provider_subs[0], U256::from(1));
-        assert_eq!(provider_subs[1], U256::from(2));
-    }
-    
-    #[test]
-    fn test_admin_functions() {
-        let mut contract = setup_contract();
-        
-        // Test pausing (would require proper msg::sender context in real test)
-        contract.paused.set(true);
-        assert_eq!(contract.paused.get(), true);
-        
-        // Test emergency stop
-        contract.emergency_stopped.set(true);
-        assert_eq!(contract.emergency_stopped.get(), true);
-    }
-    
-    #[test]
-    fn test_payment_due_logic() {
-        let mut contract = setup_contract();
-        
-        let sub_id = U256::from(1);
-        let current_time = 1700000000u64; // Mock timestamp
-        let future_time = current_time + 600; // 10 minutes later
-        
-        // Set next payment in the future
-        contract.sub_next_payment_ts.setter(sub_id).set(future_time);
-        
-        // Mock block::timestamp would be needed for real test
-        // For now, we can test the basic logic structure
-        
-        // Set next payment in the past
-        contract.sub_next_payment_ts.setter(sub_id).set(current_time - 600);
-        
-        // The is_payment_due function would return true if current_time >= next_payment_ts
+    }
+
+    deposit(&vm, &mut contract, USER_ADDR, 50_000);
+    vm.set_sender(USER_ADDR);
+    let ids: Vec<U256> = plans
+        .iter()
+        .map(|plan_id| contract.subscribe(*plan_id).unwrap())
+        .collect();
+    assert_eq!(contract.get_user_subscriptions(USER_ADDR), ids);
+
+    vm.set_block_timestamp(START_TIME + 99);
+    assert!(contract.next_due_subscriptions(U256::from(10)).is_empty());
+
+    vm.set_block_timestamp(START_TIME + 350);
+    assert_eq!(
+        contract.next_due_subscriptions(U256::from(10)),
+        vec![ids[1], ids[3], ids[4]]
+    );
+    assert_eq!(contract.next_due_subscriptions(U256::from(2)), vec![ids[1], ids[3]]);
+
+    // Paying moves a subscription back in the queue, cancelling drops it
+    vm.set_sender(ADMIN_ADDR);
+    contract.process_subscription_payment(ids[1]).unwrap();
+    assert_eq!(contract.get_subscription_next_due(ids[1]), U256::from(START_TIME + 450));
+    vm.set_sender(USER_ADDR);
+    contract.cancel_subscription(ids[3]).unwrap();
+
+    vm.set_block_timestamp(START_TIME + 500);
+    assert_eq!(
+        contract.next_due_subscriptions(U256::from(10)),
+        vec![ids[4], ids[2], ids[1], ids[0]]
+    );
+
+    let (can_exec, payload) = contract.checker(USER_ADDR);
+    assert!(can_exec);
+    assert_eq!(&payload[4..], &ids[0].to_be_bytes::<32>());
+}
+
+#[test]
+fn test_plan_interval_is_capped() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+
+    // A due time past 2^128 would otherwise sort ahead of every real renewal
+    for interval in [U256::from(MAX_PLAN_INTERVAL + 1), (U256::from(1) << 128) - U256::from(START_TIME)] {
+        assert!(matches!(
+            contract.create_plan(U256::from(1), interval),
+            Err(SubscriptionError::InvalidPlanParameters(_))
+        ));
+        assert!(matches!(
+            contract.update_plan(plan_id, U256::from(PLAN_PRICE), interval),
+            Err(SubscriptionError::InvalidPlanParameters(_))
+        ));
+    }
+    let longest = contract.create_plan(U256::from(1), U256::from(MAX_PLAN_INTERVAL)).unwrap();
+
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    contract.subscribe(longest).unwrap();
+    let due_id = contract.subscribe(plan_id).unwrap();
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    assert_eq!(contract.next_due_subscriptions(U256::from(10)), vec![due_id]);
+    let (can_exec, payload, cursor) = contract.checker_all(U256::ZERO, U256::from(10));
+    assert!(can_exec);
+    assert_eq!(&payload[4..], &due_id.to_be_bytes::<32>());
+    assert_eq!(cursor, U256::ZERO);
+}
+
+#[test]
+fn test_checker_all_pages_through_due_subscriptions() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let users: Vec<Address> = (0x10..0x15).map(Address::repeat_byte).collect();
+    contract.set_plan_grace_period(plan_id, U256::from(3 * 86_400)).unwrap();
+
+    let mut ids = Vec::new();
+    for user in &users {
+        deposit(&vm, &mut contract, *user, 2_000);
+        vm.set_sender(*user);
+        ids.push(contract.subscribe(plan_id).unwrap());
+    }
+    // An unfunded renewal is still reported, since processing it starts the grace period
+    vm.set_sender(users[1]);
+    contract.withdraw_escrow(U256::from(1_000)).unwrap();
+
+    let (can_exec, _, next_cursor) = contract.checker_all(U256::ZERO, U256::from(10));
+    assert!(!can_exec);
+    assert_eq!(next_cursor, U256::ZERO);
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    let (can_exec, payload, cursor) = contract.checker_all(U256::ZERO, U256::from(2));
+    assert!(can_exec);
+    assert_ne!(cursor, U256::ZERO);
+    // selector, offset, length, then ids 1 and 2
+    assert_eq!(payload.len(), 4 + 32 * 4);
+    assert_eq!(&payload[36..68], &U256::from(2).to_be_bytes::<32>());
+    assert_eq!(&payload[68..100], &ids[0].to_be_bytes::<32>());
+    assert_eq!(&payload[100..132], &ids[1].to_be_bytes::<32>());
+
+    // The last page reports a zero cursor once every due entry has been seen
+    let (can_exec, payload, cursor) = contract.checker_all(cursor, U256::from(3));
+    assert!(can_exec);
+    assert_eq!(&payload[68..100], &ids[2].to_be_bytes::<32>());
+    assert_eq!(&payload[100..132], &ids[3].to_be_bytes::<32>());
+    assert_eq!(&payload[132..164], &ids[4].to_be_bytes::<32>());
+    assert_eq!(cursor, U256::ZERO);
+
+    // A single due id is routed to the single-subscription processor; the past-due
+    // subscription waits out its grace period
+    vm.set_sender(ADMIN_ADDR);
+    let status = contract.process_payments_batch(vec![ids[0], ids[1], ids[2], ids[3]]).unwrap();
+    assert_eq!(status, U256::from(0b1101));
+    let (can_exec, payload, _) = contract.checker_all(U256::ZERO, U256::from(2));
+    assert!(can_exec);
+    assert_eq!(payload.len(), 4 + 32);
+    assert_eq!(&payload[4..], &ids[4].to_be_bytes::<32>());
+}
+
+#[test]
+fn test_checker_all_pages_past_scan_budget() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let count = MAX_DUE_SCAN + 300;
+    deposit(&vm, &mut contract, USER_ADDR, PLAN_PRICE * count as u64);
+
+    // Staggered over several hours so the walk crosses bucket boundaries
+    let mut ids = Vec::new();
+    for offset in 0..count as u64 {
+        vm.set_block_timestamp(START_TIME + offset * 10);
+        ids.push(contract.subscribe(plan_id).unwrap());
+    }
+
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL + count as u64 * 10);
+    let mut seen = Vec::new();
+    let mut cursor = U256::ZERO;
+    for _ in 0..count {
+        let (can_exec, payload, next_cursor) = contract.checker_all(cursor, U256::from(MAX_BATCH_SIZE));
+        assert!(can_exec);
+        let call = ISubscriptionAutomation::processPaymentsBatchCall::abi_decode(&payload, true).unwrap();
+        seen.extend(call.ids);
+
+        assert_ne!(next_cursor, cursor);
+        cursor = next_cursor;
+        if cursor.is_zero() {
+            break;
+        }
+    }
+
+    assert!(cursor.is_zero());
+    seen.sort();
+    assert_eq!(seen, ids);
+}
+
+#[test]
+fn test_checker_all_resumes_after_processed_page() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    let count = MAX_BATCH_SIZE + 44;
+    deposit(&vm, &mut contract, USER_ADDR, 2 * PLAN_PRICE * count as u64);
+
+    vm.set_sender(USER_ADDR);
+    let ids: Vec<U256> = (0..count).map(|_| contract.subscribe(plan_id).unwrap()).collect();
+
+    // Keepers charge each page before asking for the next one
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    vm.set_sender(ADMIN_ADDR);
+    let mut charged = Vec::new();
+    let mut cursor = U256::ZERO;
+    loop {
+        let (can_exec, payload, next_cursor) = contract.checker_all(cursor, U256::from(MAX_BATCH_SIZE));
+        assert!(can_exec);
+        let page = ISubscriptionAutomation::processPaymentsBatchCall::abi_decode(&payload, true).unwrap().ids;
+        let status = contract.process_payments_batch(page.clone()).unwrap();
+        assert_eq!(status.count_ones(), page.len());
+        charged.extend(page);
+
+        cursor = next_cursor;
+        if cursor.is_zero() {
+            break;
+        }
+    }
+
+    assert_eq!(charged, ids);
+    let (can_exec, _, _) = contract.checker_all(U256::ZERO, U256::from(MAX_BATCH_SIZE));
+    assert!(!can_exec);
+}
+
+#[test]
+fn test_exec_payload_selectors_match_router() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+    deposit(&vm, &mut contract, USER_ADDR, 5_000);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    let second_id = contract.subscribe(plan_id).unwrap();
+
+    // The checker payloads must dispatch to the processors through the ABI router
+    vm.set_block_timestamp(START_TIME + PLAN_INTERVAL);
+    let (can_exec, payload) = contract.checker(USER_ADDR);
+    assert!(can_exec);
+    let call =
+        ISubscriptionAutomation::processSubscriptionPaymentCall::abi_decode(&payload, true).unwrap();
+    assert_eq!(call.subscriptionId, subscription_id);
+
+    vm.set_sender(ADMIN_ADDR);
+    let output = route(&mut contract, payload[..4].try_into().unwrap(), &payload[4..]).unwrap().unwrap();
+    assert!(ISubscriptionAutomation::processSubscriptionPaymentCall::abi_decode_returns(&output, true).unwrap()._0);
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::from(5_000 - 3 * PLAN_PRICE));
+
+    vm.set_block_timestamp(START_TIME + 2 * PLAN_INTERVAL);
+    let (can_exec, payload, _) = contract.checker_all(U256::ZERO, U256::from(10));
+    assert!(can_exec);
+    let mut ids = ISubscriptionAutomation::processPaymentsBatchCall::abi_decode(&payload, true).unwrap().ids;
+    ids.sort();
+    assert_eq!(ids, vec![subscription_id, second_id]);
+
+    let output = route(&mut contract, payload[..4].try_into().unwrap(), &payload[4..]).unwrap().unwrap();
+    assert_eq!(U256::abi_decode(&output, true).unwrap(), U256::from(0b11));
+    assert_eq!(contract.get_user_balance(USER_ADDR), U256::ZERO);
+}
+
+#[test]
+fn test_internal_helpers_are_not_routed() {
+    let (vm, mut contract) = setup_contract();
+    let attacker = Address::repeat_byte(0xa7);
+    vm.set_balance(CONTRACT_ADDR, U256::from(5_000));
+    vm.set_sender(attacker);
+
+    let helpers = [
+        function_selector!("processPayment", U256, Address),
+        function_selector!("requireAdmin", Address),
+        function_selector!("requireRoleAdmin", B256, Address),
+        function_selector!("requireNotPaused"),
+        function_selector!("requireKeeper", Address),
+        function_selector!("createPlanIn", Address, U256, U256),
+        function_selector!("requireRegisteredProvider", Address),
+        function_selector!("requirePlanProvider", U256, Address),
+        function_selector!("calculateRefund", U256),
+        function_selector!("syncProtocolFee"),
+        function_selector!("planTerms", U256),
+        function_selector!("syncPlanTerms", U256),
+        function_selector!("processDeposit", Address, Address, U256),
+        function_selector!("pullIntoEscrow", Address, Address, U256),
+        function_selector!("requireAllowedToken", Address),
+        function_selector!("sendAsset", Address, Address, U256),
+        function_selector!("callToken", Address, Vec<u8>),
+        function_selector!("withdrawProviderEarningsAs", Address, Address),
+        function_selector!("withdrawProtocolFeesTo", Address, Address, U256),
+        function_selector!("withdrawKeeperEarningsAs", Address, Address),
+        function_selector!("createSubscription", Address, U256, U256, bool),
+        function_selector!("cancelSubscriptionAs", Address, U256),
+        function_selector!("withdrawEscrowAs", Address, Address, U256),
+        function_selector!("verifyIntent", Address, B256, U256, u8, B256, B256),
+    ];
+    for selector in helpers {
+        let input = (Address::ZERO, attacker, U256::from(5_000)).abi_encode();
+        assert!(route(&mut contract, selector, &input).is_none(), "helper {selector:?} is routed");
+    }
+    assert_eq!(vm.balance(attacker), U256::ZERO);
+    assert_eq!(contract.get_user_balance(attacker), U256::ZERO);
+
+    // Public entrypoints still dispatch
+    assert!(route(&mut contract, function_selector!("getAdmin"), &[]).is_some());
+}
+
+#[test]
+fn test_errors_carry_context() {
+    let (vm, mut contract) = setup_contract();
+    let plan_id = setup_plan(&vm, &mut contract);
+
+    vm.set_sender(PROVIDER_ADDR);
+    match contract.register_provider("Provider".into()) {
+        Err(SubscriptionError::ProviderAlreadyRegistered(err)) => assert_eq!(err.provider, PROVIDER_ADDR),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    vm.set_sender(USER_ADDR);
+    match contract.subscribe(U256::from(42)) {
+        Err(SubscriptionError::PlanNotFound(err)) => assert_eq!(err.planId, U256::from(42)),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    deposit(&vm, &mut contract, USER_ADDR, 1_500);
+    vm.set_sender(USER_ADDR);
+    let subscription_id = contract.subscribe(plan_id).unwrap();
+    match contract.subscribe(plan_id) {
+        Err(SubscriptionError::InsufficientEscrow(err)) => {
+            assert_eq!(err.required, U256::from(PLAN_PRICE));
+            assert_eq!(err.available, U256::from(500));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    vm.set_sender(ADMIN_ADDR);
+    match contract.process_subscription_payment(subscription_id) {
+        Err(SubscriptionError::PaymentNotDue(err)) => {
+            assert_eq!(err.subscriptionId, subscription_id);
+            assert_eq!(err.dueAt, U256::from(START_TIME + PLAN_INTERVAL));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // Only part of the deposit is backed by ETH, so the transfer fails
+    vm.set_balance(CONTRACT_ADDR, U256::from(100));
+    vm.set_sender(USER_ADDR);
+    match contract.withdraw_escrow(U256::from(500)) {
+        Err(SubscriptionError::TransferFailed(err)) => {
+            assert_eq!(err.to, USER_ADDR);
+            assert_eq!(err.amount, U256::from(500));
+        }
+        other => panic!("unexpected result: {:?}", other),
     }
 }
@@ -0,0 +1,326 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use stylus_sdk::alloy_primitives::{Address, B256, U256};
+use stylus_sdk::prelude::*;
+use stylus_sdk::stylus_core::calls::errors::Error as CallError;
+use stylus_sdk::stylus_core::calls::{CallAccess, MutatingCallContext, StaticCallContext, ValueTransfer};
+use stylus_sdk::stylus_core::deploy::DeploymentAccess;
+use stylus_sdk::testing::TestVM;
+use subscription_engine::{SubscriptionError, SubscriptionEscrow, DEFAULT_PROTOCOL_FEE_BPS};
+
+const ADMIN_ADDR: Address = Address::repeat_byte(0x01);
+const ATTACKER_ADDR: Address = Address::repeat_byte(0xa7);
+const CONTRACT_ADDR: Address = Address::repeat_byte(0xc0);
+
+const PLAN_PRICE: u64 = 1_000;
+const PLAN_INTERVAL: u64 = 86_400;
+const START_TIME: u64 = 1_700_000_000;
+
+type Attack = fn(&mut SubscriptionEscrow) -> Result<bool, SubscriptionError>;
+
+// Receiver contract that calls back into the escrow from its receive hook. The test VM
+// only moves balances on `transfer_eth`, so this host runs the callback in its place.
+#[derive(Clone)]
+struct MaliciousReceiver {
+    vm: TestVM,
+    attack: Rc<RefCell<Option<Attack>>>,
+    reentry_result: Rc<RefCell<Option<Result<bool, SubscriptionError>>>>,
+}
+
+impl MaliciousReceiver {
+    fn new(attack: Attack) -> Self {
+        let vm = TestVM::default();
+        vm.set_contract_address(CONTRACT_ADDR);
+        vm.set_block_timestamp(START_TIME);
+        Self { vm, attack: Rc::new(RefCell::new(Some(attack))), reentry_result: Rc::new(RefCell::new(None)) }
+    }
+
+    fn take_reentry_result(&self) -> Option<Result<bool, SubscriptionError>> {
+        self.reentry_result.borrow_mut().take()
+    }
+}
+
+impl Host for MaliciousReceiver {}
+
+impl ValueTransfer for MaliciousReceiver {
+    fn transfer_eth(&self, to: Address, amount: U256) -> Result<(), Vec<u8>> {
+        self.vm.transfer_eth(to, amount)?;
+        if to == ATTACKER_ADDR {
+            // Only the first payout re-enters, mirroring a receiver that attacks once
+            let attack = self.attack.borrow_mut().take();
+            if let Some(attack) = attack {
+                let mut reentered = SubscriptionEscrow::from(self);
+                *self.reentry_result.borrow_mut() = Some(attack(&mut reentered));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CryptographyAccess for MaliciousReceiver {
+    fn native_keccak256(&self, input: &[u8]) -> B256 {
+        self.vm.native_keccak256(input)
+    }
+}
+
+impl CalldataAccess for MaliciousReceiver {
+    fn read_args(&self, len: usize) -> Vec<u8> {
+        self.vm.read_args(len)
+    }
+    fn read_return_data(&self, offset: usize, size: Option<usize>) -> Vec<u8> {
+        self.vm.read_return_data(offset, size)
+    }
+    fn return_data_size(&self) -> usize {
+        self.vm.return_data_size()
+    }
+    fn write_result(&self, data: &[u8]) {
+        self.vm.write_result(data)
+    }
+}
+
+unsafe impl UnsafeDeploymentAccess for MaliciousReceiver {
+    unsafe fn create1(
+        &self,
+        code: *const u8,
+        code_len: usize,
+        endowment: *const u8,
+        contract: *mut u8,
+        revert_data_len: *mut usize,
+    ) {
+        self.vm.create1(code, code_len, endowment, contract, revert_data_len)
+    }
+    unsafe fn create2(
+        &self,
+        code: *const u8,
+        code_len: usize,
+        endowment: *const u8,
+        salt: *const u8,
+        contract: *mut u8,
+        revert_data_len: *mut usize,
+    ) {
+        self.vm.create2(code, code_len, endowment, salt, contract, revert_data_len)
+    }
+}
+
+impl StorageAccess for MaliciousReceiver {
+    fn storage_load_bytes32(&self, key: U256) -> B256 {
+        self.vm.storage_load_bytes32(key)
+    }
+    unsafe fn storage_cache_bytes32(&self, key: U256, value: B256) {
+        self.vm.storage_cache_bytes32(key, value)
+    }
+    fn flush_cache(&self, clear: bool) {
+        self.vm.flush_cache(clear)
+    }
+}
+
+unsafe impl UnsafeCallAccess for MaliciousReceiver {
+    unsafe fn call_contract(
+        &self,
+        to: *const u8,
+        data: *const u8,
+        data_len: usize,
+        value: *const u8,
+        gas: u64,
+        outs_len: &mut usize,
+    ) -> u8 {
+        self.vm.call_contract(to, data, data_len, value, gas, outs_len)
+    }
+    unsafe fn static_call_contract(
+        &self,
+        to: *const u8,
+        data: *const u8,
+        data_len: usize,
+        gas: u64,
+        outs_len: &mut usize,
+    ) -> u8 {
+        self.vm.static_call_contract(to, data, data_len, gas, outs_len)
+    }
+    unsafe fn delegate_call_contract(
+        &self,
+        to: *const u8,
+        data: *const u8,
+        data_len: usize,
+        gas: u64,
+        outs_len: &mut usize,
+    ) -> u8 {
+        self.vm.delegate_call_contract(to, data, data_len, gas, outs_len)
+    }
+}
+
+impl BlockAccess for MaliciousReceiver {
+    fn block_basefee(&self) -> U256 {
+        self.vm.block_basefee()
+    }
+    fn block_coinbase(&self) -> Address {
+        self.vm.block_coinbase()
+    }
+    fn block_number(&self) -> u64 {
+        self.vm.block_number()
+    }
+    fn block_timestamp(&self) -> u64 {
+        self.vm.block_timestamp()
+    }
+    fn block_gas_limit(&self) -> u64 {
+        self.vm.block_gas_limit()
+    }
+}
+
+impl ChainAccess for MaliciousReceiver {
+    fn chain_id(&self) -> u64 {
+        self.vm.chain_id()
+    }
+}
+
+impl AccountAccess for MaliciousReceiver {
+    fn balance(&self, account: Address) -> U256 {
+        self.vm.balance(account)
+    }
+    fn contract_address(&self) -> Address {
+        self.vm.contract_address()
+    }
+    fn code(&self, account: Address) -> Vec<u8> {
+        self.vm.code(account)
+    }
+    fn code_size(&self, account: Address) -> usize {
+        self.vm.code_size(account)
+    }
+    fn code_hash(&self, account: Address) -> B256 {
+        self.vm.code_hash(account)
+    }
+}
+
+impl MemoryAccess for MaliciousReceiver {
+    fn pay_for_memory_grow(&self, pages: u16) {
+        self.vm.pay_for_memory_grow(pages)
+    }
+}
+
+impl MessageAccess for MaliciousReceiver {
+    fn msg_sender(&self) -> Address {
+        self.vm.msg_sender()
+    }
+    fn msg_reentrant(&self) -> bool {
+        self.vm.msg_reentrant()
+    }
+    fn msg_value(&self) -> U256 {
+        self.vm.msg_value()
+    }
+    fn tx_origin(&self) -> Address {
+        self.vm.tx_origin()
+    }
+}
+
+impl MeteringAccess for MaliciousReceiver {
+    fn evm_gas_left(&self) -> u64 {
+        self.vm.evm_gas_left()
+    }
+    fn evm_ink_left(&self) -> u64 {
+        self.vm.evm_ink_left()
+    }
+    fn tx_gas_price(&self) -> U256 {
+        self.vm.tx_gas_price()
+    }
+    fn tx_ink_price(&self) -> u32 {
+        self.vm.tx_ink_price()
+    }
+}
+
+impl CallAccess for MaliciousReceiver {
+    fn static_call(&self, context: &dyn StaticCallContext, to: Address, data: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.vm.static_call(context, to, data)
+    }
+    unsafe fn delegate_call(
+        &self,
+        context: &dyn MutatingCallContext,
+        to: Address,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CallError> {
+        self.vm.delegate_call(context, to, data)
+    }
+    fn call(&self, context: &dyn MutatingCallContext, to: Address, data: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.vm.call(context, to, data)
+    }
+}
+
+impl DeploymentAccess for MaliciousReceiver {
+    unsafe fn deploy(&self, code: &[u8], endowment: U256, salt: Option<B256>) -> Result<Address, Vec<u8>> {
+        self.vm.deploy(code, endowment, salt)
+    }
+}
+
+impl LogAccess for MaliciousReceiver {
+    fn emit_log(&self, input: &[u8], num_topics: usize) {
+        self.vm.emit_log(input, num_topics)
+    }
+    fn raw_log(&self, topics: &[B256], data: &[u8]) -> Result<(), &'static str> {
+        self.vm.raw_log(topics, data)
+    }
+}
+
+// Sets up the attacker as a provider with one paid subscription's worth of earnings
+fn setup_attacker_earnings(host: &MaliciousReceiver) -> SubscriptionEscrow {
+    let mut contract = SubscriptionEscrow::from(host);
+    host.vm.set_sender(ADMIN_ADDR);
+    contract
+        .constructor(ADMIN_ADDR, ADMIN_ADDR, U256::from(DEFAULT_PROTOCOL_FEE_BPS), Address::ZERO)
+        .unwrap();
+
+    host.vm.set_sender(ATTACKER_ADDR);
+    contract.register_provider("Attacker".into()).unwrap();
+    let plan_id = contract.create_plan(U256::from(PLAN_PRICE), U256::from(PLAN_INTERVAL)).unwrap();
+    deposit(&host.vm, &mut contract, ATTACKER_ADDR, PLAN_PRICE);
+    contract.subscribe(plan_id).unwrap();
+    contract
+}
+
+fn deposit(vm: &TestVM, contract: &mut SubscriptionEscrow, user: Address, amount: u64) {
+    vm.set_sender(user);
+    vm.set_value(U256::from(amount));
+    contract.deposit().unwrap();
+    vm.set_value(U256::ZERO);
+
+    // The test VM does not move msg.value, so fund the contract by hand.
+    let held = vm.balance(CONTRACT_ADDR);
+    vm.set_balance(CONTRACT_ADDR, held + U256::from(amount));
+}
+
+#[test]
+fn test_reentrant_withdraw_during_payout_reverts() {
+    let host = MaliciousReceiver::new(|contract| contract.withdraw_provider_earnings());
+    let mut contract = setup_attacker_earnings(&host);
+    let earnings = contract.get_provider_earnings(ATTACKER_ADDR);
+    assert!(!earnings.is_zero());
+
+    assert!(contract.withdraw_provider_earnings().unwrap());
+
+    assert!(matches!(host.take_reentry_result(), Some(Err(SubscriptionError::ReentrantCall(_)))));
+    assert_eq!(host.vm.balance(ATTACKER_ADDR), earnings);
+    assert_eq!(contract.get_provider_earnings(ATTACKER_ADDR), U256::ZERO);
+}
+
+#[test]
+fn test_reentrant_deposit_during_payout_reverts() {
+    let host = MaliciousReceiver::new(|contract| contract.deposit());
+    let mut contract = setup_attacker_earnings(&host);
+    let earnings = contract.get_provider_earnings(ATTACKER_ADDR);
+    let escrow_before = contract.get_user_balance(ATTACKER_ADDR);
+
+    assert!(contract.withdraw_provider_earnings().unwrap());
+
+    assert!(matches!(host.take_reentry_result(), Some(Err(SubscriptionError::ReentrantCall(_)))));
+    assert_eq!(host.vm.balance(ATTACKER_ADDR), earnings);
+    assert_eq!(contract.get_user_balance(ATTACKER_ADDR), escrow_before);
+}
+
+#[test]
+fn test_lock_released_after_guarded_call() {
+    let host = MaliciousReceiver::new(|contract| contract.withdraw_provider_earnings());
+    let mut contract = setup_attacker_earnings(&host);
+
+    // A failed guarded call must not leave the lock held
+    assert!(contract.withdraw_escrow(U256::from(1)).is_err());
+    deposit(&host.vm, &mut contract, ATTACKER_ADDR, PLAN_PRICE);
+    assert_eq!(contract.get_user_balance(ATTACKER_ADDR), U256::from(PLAN_PRICE));
+}